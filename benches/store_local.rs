@@ -0,0 +1,105 @@
+// Benchmark of data::store_local, which partitions the output of a map operation in the files of the reduce
+// jobs, against the way it was done before: storing the partitions one by one, scanning the whole output for
+// each of them, and writing each pair to its file unbuffered, as a JSON line.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use distributedrust::common::reduce_by_key;
+use distributedrust::data::{self, REDUCE_PATH};
+use distributedrust::record::Format;
+use distributedrust::{word_count, KeyValue, Task};
+
+const INPUT_FILE: &str = "files/pg1342.txt";
+const REDUCE_JOBS: i32 = 32;
+
+type WordCountTask = Task<String, u64>;
+
+// Output of word count for the whole book, sorted by key like the output of a map operation.
+fn map_output(task: &WordCountTask, combine: bool) -> Vec<KeyValue<String, u64>> {
+    let input = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(INPUT_FILE)).unwrap();
+    let mut output = (task.map)(INPUT_FILE, &word_count::normalize(&input));
+
+    if combine {
+        reduce_by_key(&task.reduce, output)
+    } else {
+        output.sort_by(|a, b| a.key.cmp(&b.key));
+        output
+    }
+}
+
+// Store each partition in turn, scanning the whole output for the pairs of each one, as store_local did
+// before it was done in a single pass: keys are cloned to be shuffled, and each pair is serialized to its own
+// string and written to the file without buffering. Pairs are always stored as JSON lines.
+fn store_per_partition(task: &WordCountTask, id_map: i32, data: &[KeyValue<String, u64>]) -> io::Result<()> {
+    for r in 0..task.num_reduce_jobs {
+        let file_path = Path::new(REDUCE_PATH).join(data::reduce_name(id_map, r));
+        let mut file = File::create(&file_path)?;
+
+        for kv in data {
+            if (task.shuffle)(&kv.key.clone(), task.num_reduce_jobs) == r {
+                let json = serde_json::to_string(&kv)?;
+                file.write_all(json.as_bytes())?;
+                file.write_all(b"\n")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Partitions are stored in the reduce directory of the working directory, which is changed while the
+// benchmarks run. Criterion stores its reports in the original one, once they are done.
+struct WorkingDir(PathBuf);
+
+impl WorkingDir {
+    fn enter(dir: &Path) -> WorkingDir {
+        let previous = env::current_dir().unwrap();
+        env::set_current_dir(dir).unwrap();
+        WorkingDir(previous)
+    }
+}
+
+impl Drop for WorkingDir {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.0);
+    }
+}
+
+fn bench_store_local(c: &mut Criterion) {
+    let dir = env::temp_dir().join(format!("distributedrust-bench-{}", std::process::id()));
+    fs::create_dir_all(dir.join(REDUCE_PATH)).unwrap();
+
+    let mut group = c.benchmark_group(format!("store_local/{}_reduce_jobs", REDUCE_JOBS));
+
+    let mut task = Task::new_task(word_count::map_func, word_count::shuffle_func, word_count::reduce_func);
+    task.num_reduce_jobs = REDUCE_JOBS;
+
+    for (output, combine) in [("words", false), ("combined", true)] {
+        let data = map_output(&task, combine);
+        let input = format!("{}_{}_pairs", output, data.len());
+        group.throughput(Throughput::Elements(data.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("per_partition/json", &input), &data, |b, data| {
+            let _dir = WorkingDir::enter(&dir);
+            b.iter(|| store_per_partition(&task, 0, data).unwrap())
+        });
+
+        for format in [Format::Json, Format::Lz4] {
+            task.format = format;
+            let id = format!("single_pass/{}", format.name());
+            group.bench_with_input(BenchmarkId::new(id, &input), &data, |b, data| {
+                let _dir = WorkingDir::enter(&dir);
+                b.iter(|| data::store_local(&task, 0, data).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, bench_store_local);
+criterion_main!(benches);
@@ -0,0 +1,264 @@
+use clap::{Arg, ArgMatches};
+use std::time::Duration;
+
+use crate::common::RunnableTask;
+use crate::data::InputFormat;
+use crate::jobs;
+use crate::record::Format;
+use crate::worker::FailMode;
+
+// Command line settings shared by the main, master and worker binaries.
+
+// Network settings. The default port is different for each binary, so a master and a worker
+// can run on the same machine without any settings.
+pub fn network_args(default_port: &'static str) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("addr")
+            .short("a")
+            .long("addr")
+            .value_name("ADDRESS")
+            .help("IP address to listen on")
+            .takes_value(true)
+            .default_value("localhost"),
+        Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .value_name("PORT")
+            .help("TCP port to listen on")
+            .takes_value(true)
+            .default_value(default_port),
+        Arg::with_name("master")
+            .short("m")
+            .long("master")
+            .value_name("MASTER")
+            .help("Master address")
+            .takes_value(true)
+            .default_value("localhost:5000"),
+    ]
+}
+
+// Worker liveness settings
+pub fn liveness_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("heartbeat")
+            .short("b")
+            .long("heartbeat")
+            .value_name("MS")
+            .help("Interval between worker heartbeats (in milliseconds)")
+            .takes_value(true)
+            .default_value("1000"),
+        Arg::with_name("maxmissed")
+            .short("x")
+            .long("maxmissed")
+            .value_name("NUM")
+            .help("Number of heartbeats a worker can miss before it's considered dead")
+            .takes_value(true)
+            .default_value("3"),
+    ]
+}
+
+// Job settings, for the nodes that choose the job
+pub fn job_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("job")
+            .short("j")
+            .long("job")
+            .value_name("JOB")
+            .help("Job to run: wordcount, grep, invertedindex or distinctcount")
+            .takes_value(true)
+            .default_value(jobs::DEFAULT_JOB),
+        Arg::with_name("jobargs")
+            .short("g")
+            .long("jobargs")
+            .value_name("ARGS")
+            .help("Arguments of the job, such as the pattern of grep")
+            .takes_value(true)
+            .default_value(""),
+    ]
+}
+
+// Number of reduce jobs
+pub fn reduce_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("reducejobs")
+            .short("r")
+            .long("reducejobs")
+            .value_name("NUM")
+            .help("Number of reduce jobs that should be run")
+            .takes_value(true)
+            .default_value("5"),
+    ]
+}
+
+// Input data settings
+pub fn input_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("file")
+            .short("f")
+            .long("file")
+            .value_name("FILE")
+            .help("Files, directories or glob patterns to use as input")
+            .takes_value(true)
+            .multiple(true)
+            .default_value("files/pg1342.txt"),
+        Arg::with_name("chunksize")
+            .short("c")
+            .long("chunksize")
+            .value_name("SIZE")
+            .help("Size of data chunks that should be passed to map jobs (in bytes)")
+            .takes_value(true)
+            .default_value("102400"), // Corresponds to 100*1024
+    ]
+}
+
+pub fn input_format_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("inputformat")
+            .short("l")
+            .long("inputformat")
+            .value_name("FORMAT")
+            .help("Format of the input file: text, lines, csv or jsonl. Splits never cut a line, CSV row or JSON object")
+            .takes_value(true)
+            .default_value("text"),
+    ]
+}
+
+// Input transfer settings
+pub fn transfer_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("stream")
+            .short("s")
+            .long("stream")
+            .help("Send the content of the input chunks to the workers, instead of their paths"),
+    ]
+}
+
+// Intermediate file settings
+pub fn format_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("format")
+            .short("i")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Format of the map outputs: json, binary or lz4 (compressed binary)")
+            .takes_value(true)
+            .default_value("json"),
+    ]
+}
+
+// Induced failure on Worker
+pub fn failure_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("fail")
+            .short("n")
+            .long("fail")
+            .value_name("NUM")
+            .help("Number of operations to run before failure")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("failmode")
+            .short("e")
+            .long("failmode")
+            .value_name("MODE")
+            .help("Induced failure mode: exit, hang or error")
+            .takes_value(true)
+            .default_value("exit"),
+    ]
+}
+
+// Address the node listens on, which is also the address other nodes use to reach it.
+pub fn hostname(matches: &ArgMatches) -> String {
+    let addr = matches.value_of("addr").unwrap_or("localhost");
+    let port = matches
+        .value_of("port")
+        .unwrap_or("5000")
+        .parse::<u16>()
+        .unwrap(); // Parse to u16
+
+    format!("{}:{}", addr, port)
+}
+
+pub fn master_hostname(matches: &ArgMatches) -> String {
+    matches.value_of("master").unwrap_or("localhost:5000").to_string()
+}
+
+pub fn reduce_jobs(matches: &ArgMatches) -> i32 {
+    matches
+        .value_of("reducejobs")
+        .unwrap_or("5")
+        .parse::<i32>()
+        .unwrap() // Parse to i32
+}
+
+// Files, directories and glob patterns given as input.
+pub fn inputs<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+    matches
+        .values_of("file")
+        .map(|values| values.collect())
+        .unwrap_or_else(|| vec!["files/pg1342.txt"])
+}
+
+pub fn chunk_size(matches: &ArgMatches) -> usize {
+    matches
+        .value_of("chunksize")
+        .unwrap_or("102400")
+        .parse::<usize>()
+        .unwrap() // Parse to usize
+}
+
+// Heartbeat interval and number of heartbeats a worker can miss.
+pub fn liveness(matches: &ArgMatches) -> (Duration, u32) {
+    let heartbeat_interval = matches
+        .value_of("heartbeat")
+        .unwrap_or("1000")
+        .parse::<u64>()
+        .unwrap(); // Parse to u64
+
+    let max_missed_heartbeats = matches
+        .value_of("maxmissed")
+        .unwrap_or("3")
+        .parse::<u32>()
+        .unwrap(); // Parse to u32
+
+    (Duration::from_millis(heartbeat_interval), max_missed_heartbeats)
+}
+
+// Task of the chosen job.
+pub fn job_task(matches: &ArgMatches) -> Result<Box<dyn RunnableTask>, String> {
+    let job = matches.value_of("job").unwrap_or(jobs::DEFAULT_JOB);
+    let job_args = matches.value_of("jobargs").unwrap_or("");
+
+    jobs::new_task(job, job_args)
+}
+
+// Format of the input file, which tells how to split it.
+pub fn input_format(matches: &ArgMatches) -> Result<InputFormat, String> {
+    let format = matches.value_of("inputformat").unwrap_or("text");
+    InputFormat::from_name(format).ok_or_else(|| format!("Invalid input format: {}", format))
+}
+
+// Whether input chunks are sent to the workers, so they don't need access to the master files.
+pub fn stream_inputs(matches: &ArgMatches) -> bool {
+    matches.is_present("stream")
+}
+
+// Format the map outputs are stored in.
+pub fn format(matches: &ArgMatches) -> Result<Format, String> {
+    let format = matches.value_of("format").unwrap_or("json");
+    Format::from_name(format).ok_or_else(|| format!("Invalid intermediate format: {}", format))
+}
+
+// Number of operations to run before failure, and how to fail.
+pub fn failure(matches: &ArgMatches) -> Result<(i32, FailMode), String> {
+    let n_ops = matches
+        .value_of("fail")
+        .unwrap_or("0")
+        .parse::<i32>()
+        .unwrap(); // Parse to i32
+
+    let fail_mode = matches.value_of("failmode").unwrap_or("exit");
+    match FailMode::from_name(fail_mode) {
+        Some(fail_mode) => Ok((n_ops, fail_mode)),
+        None => Err(format!("Invalid fail mode: {}", fail_mode)),
+    }
+}
@@ -1,47 +1,105 @@
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::hash::Hash;
+use std::io;
+use std::path;
 use tokio::sync::mpsc::{self, Sender, Receiver};
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct KeyValue {
-    pub key: String,
-    pub value: String,
+use crate::data;
+use crate::merge::SortedRuns;
+use crate::record::Format;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyValue<K, V> {
+    pub key: K,
+    pub value: V,
 }
 
+// Key is implemented by the types that can be used as keys of a Task. Keys are stored between the map and
+// reduce phases, hashed to choose their reduce job, and sorted.
+pub trait Key: Serialize + DeserializeOwned + Hash + Ord + Clone + Send + Sync + 'static {}
+
+impl<T> Key for T where T: Serialize + DeserializeOwned + Hash + Ord + Clone + Send + Sync + 'static {}
+
+// Value is implemented by the types that can be used as values of a Task.
+pub trait Value: Serialize + DeserializeOwned + Clone + Send + Sync + 'static {}
+
+impl<T> Value for T where T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static {}
+
+#[derive(Debug, Clone)]
 pub struct Operation {
     pub proc: String,
     pub file_path: String,
     pub id: i32,
+    pub attempts: u32,  // Number of times the operation failed because of its input
 }
 
-// Task is the exposed struct of the Framework that the calling code should initialize
-// with the specific implementation of the operation.
+// OperationError tells apart the errors reading the input of an operation from the errors storing its result.
 #[derive(Debug)]
-pub struct Task {
+pub enum OperationError {
+    Input(io::Error),
+    Storage(io::Error),
+}
+
+// Summary of the result of a reduce operation.
+#[derive(Debug, Clone, Copy)]
+pub struct ReduceSummary {
+    pub records: usize,     // Number of key/value pairs stored
+    pub bytes: u64,         // Size of the stored result
+}
+
+// Task is the exposed struct of the Framework that the calling code should initialize
+// with the specific implementation of the operation. The functions can be closures, so they can capture
+// the configuration of the job.
+pub struct Task<K: Key, V: Value> {
     // MapReduce functions
-    pub map: MapFunc,
-    pub shuffle: ShuffleFunc,
-    pub reduce: ReduceFunc,
+    pub map: MapFunc<K, V>,
+    pub shuffle: ShuffleFunc<K>,
+    pub reduce: ReduceFunc<K, V>,
+    pub combine: Option<ReduceFunc<K, V>>,  // Reduces the output of each map operation before it's stored
+    pub preprocess: Option<PreprocessFunc>, // Transforms each input before it's mapped
+
+    // Name and arguments of the job, sent to workers when they register
+    pub job: String,
+    pub job_args: String,
+
+    // Format of the map outputs, also sent to workers when they register
+    pub format: Format,
 
     // Jobs
     pub num_reduce_jobs: i32,
     pub num_map_files: i32,
 
     // Channels for data
-    pub input_chan: Receiver<Vec<u8>>,
-    pub output_chan: Sender<Vec<KeyValue>>,
+    pub input_chan: Receiver<(String, Vec<u8>)>,   // Name and content of each input
+    pub output_chan: Sender<Vec<KeyValue<K, V>>>,
 
     // Channels for file paths
     pub input_file_path_chan: Receiver<String>,
     pub output_file_path_chan: Sender<String>,
 }
 
-impl Task {
-    pub fn new_task(map: MapFunc, shuffle: ShuffleFunc, reduce: ReduceFunc) -> Task {
-        let task = Task {
+impl<K: Key, V: Value> Task<K, V> {
+    pub fn new_task<M, S, R>(map: M, shuffle: S, reduce: R) -> Task<K, V>
+    where
+        M: Fn(&str, &[u8]) -> Vec<KeyValue<K, V>> + Send + Sync + 'static,
+        S: Fn(&K, i32) -> i32 + Send + Sync + 'static,
+        R: Fn(&K, &mut dyn Iterator<Item = V>) -> V + Send + Sync + 'static,
+    {
+        Task {
             // Map and reduce functions
-            map,
-            shuffle,
-            reduce,
+            map: Box::new(map),
+            shuffle: Box::new(shuffle),
+            reduce: Box::new(reduce),
+            combine: None,
+            preprocess: None,
+
+            // Job
+            job: String::new(),
+            job_args: String::new(),
+
+            // Intermediate files
+            format: Format::Json,
 
             // Jobs
             num_reduce_jobs: 0,
@@ -54,12 +112,317 @@ impl Task {
             // Channels for file paths
             input_file_path_chan: mpsc::channel(1).1,
             output_file_path_chan: mpsc::channel(1).0,
+        }
+    }
+
+    // Set a combiner, which runs over the output of each map operation before it's stored. It gets the
+    // values of each key in the output, so it must be safe to reduce its result again: usually, it's the
+    // reduce function itself.
+    pub fn with_combiner<C>(mut self, combine: C) -> Task<K, V>
+    where
+        C: Fn(&K, &mut dyn Iterator<Item = V>) -> V + Send + Sync + 'static,
+    {
+        self.combine = Some(Box::new(combine));
+        self
+    }
+
+    // Set a preprocessor, which transforms each input before map runs on it, such as normalizing text. Inputs
+    // are split with their original content, so jobs that need it don't set any.
+    pub fn with_preprocessor<P>(mut self, preprocess: P) -> Task<K, V>
+    where
+        P: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.preprocess = Some(Box::new(preprocess));
+        self
+    }
+}
+
+// RunnableTask is implemented by every Task, whatever its key and value types are. The framework runs
+// tasks through it, so the job can be chosen at runtime.
+pub trait RunnableTask: Send + Sync {
+    fn job(&self) -> &str;
+    fn job_args(&self) -> &str;
+    fn set_job(&mut self, job: &str, job_args: &str);
+
+    fn format(&self) -> Format;
+    fn set_format(&mut self, format: Format);
+
+    fn num_reduce_jobs(&self) -> i32;
+    fn set_num_reduce_jobs(&mut self, num_reduce_jobs: i32);
+
+    fn input_chan(&mut self) -> &mut Receiver<(String, Vec<u8>)>;
+    fn input_file_path_chan(&mut self) -> &mut Receiver<String>;
+
+    // Run map on an input, after preprocessing it, combine its output and store the result locally, split by
+    // reduce job.
+    fn run_map(&self, id_map: i32, name: &str, input: &[u8]) -> Result<(), OperationError>;
+
+    // Merge the sorted map outputs of a reduce job, run reduce for each key and store the result. The map
+    // outputs are streamed from disk, so they don't need to fit in memory.
+    fn run_reduce(&self, id_reduce: i32, runs: Vec<path::PathBuf>) -> Result<ReduceSummary, OperationError>;
+}
+
+impl<K: Key, V: Value> RunnableTask for Task<K, V> {
+    fn job(&self) -> &str {
+        &self.job
+    }
+
+    fn job_args(&self) -> &str {
+        &self.job_args
+    }
+
+    fn set_job(&mut self, job: &str, job_args: &str) {
+        self.job = job.to_string();
+        self.job_args = job_args.to_string();
+    }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    fn num_reduce_jobs(&self) -> i32 {
+        self.num_reduce_jobs
+    }
+
+    fn set_num_reduce_jobs(&mut self, num_reduce_jobs: i32) {
+        self.num_reduce_jobs = num_reduce_jobs;
+    }
+
+    fn input_chan(&mut self) -> &mut Receiver<(String, Vec<u8>)> {
+        &mut self.input_chan
+    }
+
+    fn input_file_path_chan(&mut self) -> &mut Receiver<String> {
+        &mut self.input_file_path_chan
+    }
+
+    fn run_map(&self, id_map: i32, name: &str, input: &[u8]) -> Result<(), OperationError> {
+        let mut map_result = match self.preprocess {
+            Some(ref preprocess) => (self.map)(name, &preprocess(input)),
+            None => (self.map)(name, input),
         };
 
-        return task;
+        // Map outputs are stored sorted by key, so reduce jobs can merge them.
+        match self.combine {
+            Some(ref combine) => map_result = reduce_by_key(combine, map_result),
+            None => map_result.sort_by(|a, b| a.key.cmp(&b.key)),
+        }
+
+        data::store_local(self, id_map, &map_result).map_err(OperationError::Storage)
     }
+
+    fn run_reduce(&self, id_reduce: i32, runs: Vec<path::PathBuf>) -> Result<ReduceSummary, OperationError> {
+        let map_result = SortedRuns::<K, V>::open(id_reduce, runs, self.format).map_err(OperationError::Input)?;
+        let mut file = data::create_result(id_reduce).map_err(OperationError::Storage)?;
+
+        let mut summary = ReduceSummary {
+            records: 0,
+            bytes: 0,
+        };
+        reduce_sorted(&self.reduce, map_result, |kv| {
+            summary.bytes += file.write(&kv)?;
+            summary.records += 1;
+            Ok(())
+        })?;
+        file.finish().map_err(OperationError::Storage)?;
+
+        Ok(summary)
+    }
+}
+
+pub type MapFunc<K, V> = Box<dyn Fn(&str, &[u8]) -> Vec<KeyValue<K, V>> + Send + Sync>;  // Name and content of the input
+pub type ReduceFunc<K, V> = Box<dyn Fn(&K, &mut dyn Iterator<Item = V>) -> V + Send + Sync>;   // Key and its values
+pub type ShuffleFunc<K> = Box<dyn Fn(&K, i32) -> i32 + Send + Sync>;     // Key and number of reduce jobs
+pub type PreprocessFunc = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;  // Content of the input
+
+// Sort the key/value pairs by key, and run reduce once for each key with all of its values. The result is
+// sorted by key.
+pub fn reduce_by_key<K: Key, V: Value>(reduce: &ReduceFunc<K, V>, mut data: Vec<KeyValue<K, V>>) -> Vec<KeyValue<K, V>> {
+    data.sort_by(|a, b| a.key.cmp(&b.key));
+
+    // Neither reading the pairs from memory nor keeping the result can fail.
+    let mut result = Vec::new();
+    let _ = reduce_sorted(reduce, data.into_iter().map(Ok), |kv| {
+        result.push(kv);
+        Ok(())
+    });
+
+    result
 }
 
-type MapFunc = fn(&Vec<u8>) -> Vec<KeyValue>;
-type ReduceFunc = fn(&mut Vec<KeyValue>) -> &mut Vec<KeyValue>;
-type ShuffleFunc = fn(&Task, String) -> i32;
+// Run reduce once for each key of key/value pairs sorted by key, and pass each result to emit. Values are
+// streamed to reduce as they are read, so the values of a key don't need to fit in memory either.
+pub fn reduce_sorted<K, V, I, E>(reduce: &ReduceFunc<K, V>, data: I, mut emit: E) -> Result<(), OperationError>
+where
+    K: Key,
+    V: Value,
+    I: Iterator<Item = io::Result<KeyValue<K, V>>>,
+    E: FnMut(KeyValue<K, V>) -> io::Result<()>,
+{
+    let mut data = data.peekable();
+    while let Some(first) = data.next() {
+        let first = first.map_err(OperationError::Input)?;
+        let key = first.key;
+
+        let mut error = None;
+        let value = {
+            let mut values = std::iter::once(first.value).chain(std::iter::from_fn(|| {
+                match data.next_if(|kv| kv.as_ref().map_or(true, |kv| kv.key == key))? {
+                    Ok(kv) => Some(kv.value),
+                    Err(e) => {
+                        error = Some(e);
+                        None
+                    }
+                }
+            }));
+
+            let value = reduce(&key, &mut values);
+
+            // Values the reduce function didn't use still belong to this key.
+            values.for_each(drop);
+            value
+        };
+
+        if let Some(e) = error {
+            return Err(OperationError::Input(e));
+        }
+        emit(KeyValue { key, value }).map_err(OperationError::Storage)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{InputFormat, REDUCE_PATH};
+    use crate::record::RecordReader;
+    use crate::test_cluster::RunDir;
+    use crate::word_count;
+    use std::collections::BTreeMap;
+    use std::fs::{self, File};
+    use std::io::BufReader;
+
+    fn sum(_: &String, values: &mut dyn Iterator<Item = u64>) -> u64 {
+        values.sum()
+    }
+
+    fn pair(key: &str, value: u64) -> io::Result<KeyValue<String, u64>> {
+        Ok(KeyValue { key: key.to_string(), value })
+    }
+
+    fn run_reduce_sorted(
+        reduce: ReduceFunc<String, u64>,
+        data: Vec<io::Result<KeyValue<String, u64>>>,
+    ) -> (Result<(), OperationError>, Vec<(String, u64)>) {
+        let mut result = Vec::new();
+        let status = reduce_sorted(&reduce, data.into_iter(), |kv| {
+            result.push((kv.key, kv.value));
+            Ok(())
+        });
+        (status, result)
+    }
+
+    #[test]
+    fn reduce_sorted_once_per_key() {
+        let data = vec![pair("a", 1), pair("a", 2), pair("b", 3), pair("c", 4), pair("c", 5)];
+        let (status, result) = run_reduce_sorted(Box::new(sum), data);
+
+        assert!(status.is_ok());
+        assert_eq!(result, vec![("a".to_string(), 3), ("b".to_string(), 3), ("c".to_string(), 9)]);
+    }
+
+    #[test]
+    fn reduce_sorted_error_in_values() {
+        let error = io::Error::new(io::ErrorKind::InvalidData, "corrupt");
+        let data = vec![pair("a", 1), pair("b", 2), pair("b", 3), Err(error), pair("b", 4), pair("c", 5)];
+        let (status, result) = run_reduce_sorted(Box::new(sum), data);
+
+        // The key whose values were cut short isn't emitted with a partial result.
+        assert!(matches!(status, Err(OperationError::Input(ref e)) if e.kind() == io::ErrorKind::InvalidData));
+        assert_eq!(result, vec![("a".to_string(), 1)]);
+    }
+
+    #[test]
+    fn reduce_sorted_unused_values() {
+        // Reduce only takes the first value of each key: the rest must not be taken for another key.
+        let first = |_: &String, values: &mut dyn Iterator<Item = u64>| values.next().unwrap();
+        let data = vec![pair("a", 1), pair("a", 2), pair("a", 3), pair("b", 4), pair("b", 5), pair("c", 6)];
+        let (status, result) = run_reduce_sorted(Box::new(first), data);
+
+        assert!(status.is_ok());
+        assert_eq!(result, vec![("a".to_string(), 1), ("b".to_string(), 4), ("c".to_string(), 6)]);
+    }
+
+    #[test]
+    fn reduce_sorted_storage_error() {
+        let data = vec![pair("a", 1), pair("b", 2)];
+        let status = reduce_sorted(&(Box::new(sum) as ReduceFunc<String, u64>), data.into_iter(), |_| {
+            Err(io::Error::other("disk full"))
+        });
+
+        assert!(matches!(status, Err(OperationError::Storage(_))));
+    }
+
+    #[test]
+    fn reduce_by_key_sorts_and_groups() {
+        let data = ["b", "a", "c", "a", "b", "a"].iter()
+            .map(|key| KeyValue { key: key.to_string(), value: 1 })
+            .collect();
+        let result = reduce_by_key(&(Box::new(sum) as ReduceFunc<String, u64>), data);
+
+        let result: Vec<_> = result.into_iter().map(|kv| (kv.key, kv.value)).collect();
+        assert_eq!(result, vec![("a".to_string(), 3), ("b".to_string(), 2), ("c".to_string(), 1)]);
+    }
+
+    // Run map over every split of the book with the default chunk size, returning the total of each word in
+    // the stored partitions and their size.
+    fn map_book(task: &Task<String, u64>) -> (BTreeMap<String, u64>, u64) {
+        let book = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("files/pg1342.txt");
+        let splits = data::split_data(&[book], 102400, InputFormat::Text);
+
+        let mut counts = BTreeMap::new();
+        let mut bytes = 0;
+        for (id_map, split) in splits.iter().enumerate() {
+            let input = fs::read(&split.file_path).unwrap();
+            task.run_map(id_map as i32, &split.file_path, &input).unwrap();
+
+            for id_reduce in 0..task.num_reduce_jobs {
+                let file_path = path::Path::new(REDUCE_PATH).join(data::reduce_name(id_map as i32, id_reduce));
+                bytes += fs::metadata(&file_path).unwrap().len();
+
+                let reader = RecordReader::<_, String, u64>::new(BufReader::new(File::open(&file_path).unwrap()));
+                for kv in reader.unwrap() {
+                    let kv = kv.unwrap();
+                    *counts.entry(kv.key).or_insert(0) += kv.value;
+                }
+            }
+        }
+
+        (counts, bytes)
+    }
+
+    #[tokio::test]
+    async fn combiner_keeps_counts() {
+        let _run = RunDir::enter("combiner").await;
+
+        let new_task = || {
+            let mut task = Task::new_task(word_count::map_func, word_count::shuffle_func, word_count::reduce_func)
+                .with_preprocessor(word_count::normalize);
+            task.num_reduce_jobs = 5;
+            task
+        };
+        let (counts, bytes) = map_book(&new_task());
+        let (combined_counts, combined_bytes) = map_book(&new_task().with_combiner(word_count::reduce_func));
+
+        assert_eq!(combined_counts, counts);
+
+        // The README tells it's about 6 times less data.
+        let ratio = bytes as f64 / combined_bytes as f64;
+        assert!((5.0..7.0).contains(&ratio), "the combiner stores {:.1} times less data", ratio);
+    }
+}
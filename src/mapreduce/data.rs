@@ -1,15 +1,14 @@
 use std::fs::{self, File};
-use std::io::{self, BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path;
-use tokio::sync::Mutex;
 use std::thread::sleep;
 use std::time::Duration;
 
-use tokio::sync::mpsc::{self, Sender, Receiver};
-use serde_json;
-use text_splitter;
+use serde::{Serialize, Deserialize};
+use tokio::sync::mpsc::{self, Receiver};
 
-use crate::common::{self, KeyValue};
+use crate::common::{self, Key, KeyValue, Value};
+use crate::record::{Format, RecordWriter};
 
 pub const MAP_PATH: &str = "map";
 pub const REDUCE_PATH: &str = "reduce";
@@ -17,9 +16,41 @@ pub const RESULT_PATH: &str = "result";
 pub const OPEN_FILE_MAX_RETRY: u8 = 3;
 
 const MAP_BUFFER_SIZE: usize = 10;
-const REDUCE_BUFFER_SIZE: usize = 10;
+const SPLITS_FILE: &str = "splits";
+
+// Split is a part of an input file, stored in its own map file. Map operations are given the name of the
+// input file, so jobs such as the inverted index can tell documents apart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Split {
+    pub file_path: String,  // Map file with the content of the split
+    pub source: String,     // Input file the split was read from
+    pub offset: u64,        // Position of the split in the input file (in bytes)
+    pub size: u64,          // Number of bytes of the input file in the split
+}
+
+// InputFormat is the layout of the input file. It tells split_data where its records end, so a record is
+// never split across map inputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Text,       // Prose, split between words.
+    Lines,      // One record per line, such as log files.
+    Csv,        // CSV with a header line, which is repeated at the start of every split.
+    JsonLines,  // One JSON object per line.
+}
+
+impl InputFormat {
+    pub fn from_name(name: &str) -> Option<InputFormat> {
+        match name {
+            "text" => Some(InputFormat::Text),
+            "lines" => Some(InputFormat::Lines),
+            "csv" => Some(InputFormat::Csv),
+            "jsonl" => Some(InputFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
 
-// Returns the name of files created after merge
+// Returns the name of the input of a reduce job, which stands for all the map outputs it merges
 // NOTE: TESTED
 pub fn merge_reduce_name(id_reduce: i32) -> String {
     format!("reduce-{id_reduce}")
@@ -31,59 +62,43 @@ pub fn reduce_name(id_map: i32, id_reduce: i32) -> String {
     format!("reduce-{id_map}-{id_reduce}")
 }
 
-// Store result from map operation locally.
-// This will store the result from all the map calls.
+// Store result from map operation locally, in the intermediate format of the task.
+// This will store the result from all the map calls. The data must be sorted by key, so every file is a
+// sorted run that reduce jobs can merge with the runs of the other map operations.
+// Every partition is open while the data is written, so each pair is shuffled and written once, in order.
 // NOTE: TESTED
-pub fn store_local(task: &common::Task, id_map_task: i32, data: &Vec<common::KeyValue>) -> io::Result<()> {
-    for r in 0..task.num_reduce_jobs {
-        let file_path = path::Path::new(REDUCE_PATH).join(reduce_name(id_map_task, r));
-        let mut file = File::create(&file_path).expect("Error creating file");
-        
-        for kv in data {
-            if (task.shuffle)(task, kv.key.clone()) == r {
-                let json = serde_json::to_string(&kv)?;
-                file.write_all(json.as_bytes())?;
-                file.write_all(b"\n")?;
-            }
-        }
+pub fn store_local<K: Key, V: Value>(task: &common::Task<K, V>, id_map_task: i32, data: &Vec<KeyValue<K, V>>)
+                                     -> io::Result<()> {
+    let mut files = (0..task.num_reduce_jobs)
+        .map(|r| {
+            let file_path = path::Path::new(REDUCE_PATH).join(reduce_name(id_map_task, r));
+            RecordWriter::new(BufWriter::new(File::create(&file_path)?), task.format)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    for kv in data {
+        let r = (task.shuffle)(&kv.key, task.num_reduce_jobs);
+        let file = usize::try_from(r).ok()
+            .and_then(|r| files.get_mut(r))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Shuffle chose reduce job {} out of {}", r, task.num_reduce_jobs)))?;
+        file.write(kv)?;
     }
 
-    Ok(())
-}
-
-// Merge the result from all the map operations by reduce job id.
-// NOTE: TESTED
-pub fn merge_map_local(task: &common::Task, map_counter: i32) -> io::Result<()> {
-    for r in 0..task.num_reduce_jobs {
-        let merged_file_path = path::Path::new(REDUCE_PATH).join(merge_reduce_name(r));
-        let mut merged_file = File::create(merged_file_path)?;
-
-        for m in 0..map_counter {
-            // Use max number of retries to open the file
-            let file_path = path::Path::new(REDUCE_PATH).join(reduce_name(m, r));
-            for i in 0..OPEN_FILE_MAX_RETRY {
-                if let Ok(_f) = File::open(&file_path) {
-                    // Read from the file
-                    break;
-                }
-                eprintln!(
-                    "({}/{}) Failed to open file {}. Retrying in 1 second...",
-                    i + 1,
-                    OPEN_FILE_MAX_RETRY,
-                    file_path.display()
-                );
-                sleep(Duration::from_secs(1));
-            }
-
-            let mut file = File::open(&file_path)?;
-            // Read the contents of the file and write to the destination file
-            io::copy(&mut file, &mut merged_file)?;
-        }
+    for file in files {
+        file.finish()?;
     }
 
     Ok(())
 }
 
+// Create the file with the result of a reduce operation. Results are always stored as JSON, whatever the
+// intermediate format is, so they can be merged into the final result and read by people.
+pub fn create_result(id_reduce: i32) -> io::Result<RecordWriter<BufWriter<File>>> {
+    let file_path = result_file_name(id_reduce);
+    RecordWriter::new(BufWriter::new(File::create(&file_path)?), Format::Json)
+}
+
 // Merge the result from all the map operations by reduce job id.
 // NOTE: TESTED
 pub fn merge_reduce_local(reduce_counter: i32) -> io::Result<()> {
@@ -115,23 +130,6 @@ pub fn merge_reduce_local(reduce_counter: i32) -> io::Result<()> {
     Ok(())
 }
 
-// Load data for reduce jobs.
-// NOTE: TESTED
-pub fn load_local(id_reduce: i32) -> io::Result<Vec<common::KeyValue>> {
-    let file_path = path::Path::new(REDUCE_PATH).join(merge_reduce_name(id_reduce));
-
-    let file = File::open(&file_path)?;
-    let reader = BufReader::new(file);
-
-    let mut data = Vec::new();
-    for result in serde_json::Deserializer::from_reader(reader).into_iter::<common::KeyValue>() {
-        let kv = result?;
-        data.push(kv);
-    }
-
-    Ok(data)
-}
-
 // Remove all the files in a directory
 // NOTE: TESTED
 pub fn remove_contents(dir: &str) -> io::Result<()> {
@@ -162,7 +160,7 @@ pub fn fan_reduce_file_path(num_reduce_jobs: i32) -> Receiver<String> {
             let file_path = path::Path::new(REDUCE_PATH).join(merge_reduce_name(i));
 
             if let Some(file_path_str) = file_path.to_str() {
-                if let Err(_) = output_tx.send(file_path_str.to_string()).await {
+                if output_tx.send(file_path_str.to_string()).await.is_err() {
                     break;
                 }
             }
@@ -174,49 +172,227 @@ pub fn fan_reduce_file_path(num_reduce_jobs: i32) -> Receiver<String> {
     output_rx
 }
 
-// Reads input file and split it into files smaller than chunkSize.
-pub fn split_data(file_name: &str, chunk_size: usize) -> usize {
-    let mut file = match File::open(file_name) {
-        Ok(file) => file,
-        Err(e) => {
-            eprintln!("Error opening file {}: {}", file_name, e);
-            return 0;
+// Expand the inputs given in the command line into the files they stand for: a directory stands for the
+// files in it, and a glob pattern for the files it matches. Files are listed in the order of their inputs,
+// sorted by name within each directory or pattern.
+pub fn input_files(inputs: &[&str]) -> Result<Vec<path::PathBuf>, String> {
+    let mut files = Vec::new();
+
+    for input in inputs {
+        let input_path = path::Path::new(input);
+        let mut matched: Vec<path::PathBuf> = if input_path.is_dir() {
+            fs::read_dir(input_path)
+                .map_err(|e| format!("Error reading directory {}: {}", input, e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect()
+        } else if input_path.exists() {
+            vec![input_path.to_path_buf()]
+        } else {
+            glob::glob(input)
+                .map_err(|e| format!("Invalid input pattern {}: {}", input, e))?
+                .filter_map(Result::ok)
+                .filter(|path| path.is_file())
+                .collect()
+        };
+
+        if matched.is_empty() {
+            return Err(format!("No input files found for {}", input));
         }
-    };
 
-    // Read the file content into a String
-    let mut file_content = String::new();
-    if let Err(e) = file.read_to_string(&mut file_content) {
-        eprintln!("Error reading file {}: {}", file_name, e);
-        return 0;
+        matched.sort();
+        files.append(&mut matched);
     }
 
-    file_content = file_content.to_ascii_lowercase().replace(&['(', ')', ',', '\"', '.', ';', ':', '\'', '`', '-'][..], "");
-    file_content = file_content.to_ascii_lowercase().replace('\n', " ");
+    Ok(files)
+}
 
-    let splitter = text_splitter::TextSplitter::default().with_trim_chunks(false);
-    let chunks = splitter.chunks(file_content.as_str(), chunk_size);
+// Reads the input files and split them into files smaller than chunkSize, which are stored in MAP_PATH along
+// with an index of the splits. Returns the splits, in the order of their map files.
+// Inputs made of records are split between records, see split_records.
+pub fn split_data(file_names: &[path::PathBuf], chunk_size: usize, format: InputFormat) -> Vec<Split> {
+    let mut splits = Vec::new();
 
-    let mut num_chunks = 0;
-    for (i, chunk) in chunks.enumerate() {
-        let output_file_name = map_file_name(i as i32);
-        let mut output_file = match File::create(&output_file_name) {
-            Ok(file) => file,
-            Err(e) => {
-                eprintln!("Error creating file: {}", e);
-                return num_chunks;
-            }
+    for file_name in file_names {
+        let result = match format {
+            InputFormat::Text => split_text(file_name, chunk_size, &mut splits),
+            _ => split_records(file_name, chunk_size, format, &mut splits),
         };
 
-        if let Err(e) = write!(&mut output_file, "{}", chunk) {
-            eprintln!("Error writing to file: {}", e);
-            return num_chunks;
+        if let Err(e) = result {
+            eprintln!("Error splitting file {}: {}", file_name.display(), e);
         }
+    }
+
+    if let Err(e) = store_splits(&splits) {
+        eprintln!("Error storing the index of splits: {}", e);
+    }
+
+    let total_size: u64 = splits.iter().map(|split| split.size).sum();
+    println!("Split {} input files ({} bytes) into {} map inputs.", file_names.len(), total_size, splits.len());
+
+    splits
+}
+
+// Split prose between words, reading a split at a time, so the input doesn't need to fit in memory. Splits
+// keep the original content of the input: jobs that need it normalized set a preprocessor.
+fn split_text(file_name: &path::Path, chunk_size: usize, splits: &mut Vec<Split>) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    let chunk_size = chunk_size.max(1);
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut offset = 0;
+
+    loop {
+        // Fill the split up to chunk_size bytes, after the end of the previous one that didn't fit in it.
+        let missing = (chunk_size - chunk.len()) as u64;
+        (&mut reader).take(missing).read_to_end(&mut chunk)?;
+        let end_of_file = chunk.len() < chunk_size;
+
+        if chunk.is_empty() {
+            break;
+        }
+
+        let size = if end_of_file { chunk.len() } else { split_point(&chunk) };
+        store_split(splits, file_name, offset, size as u64, &chunk[..size])?;
+        offset += size as u64;
+        chunk.drain(..size);
+
+        if end_of_file {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Position a split of prose ends at: after its last whitespace, so no word is cut. A word longer than a split
+// is cut, but not in the middle of a UTF-8 character.
+fn split_point(chunk: &[u8]) -> usize {
+    match chunk.iter().rposition(u8::is_ascii_whitespace) {
+        Some(position) => position + 1,
+        None => {
+            // Only the last character can be cut, in which case the split ends before it.
+            let last = (1..chunk.len()).rev().find(|&i| chunk[i] & 0xC0 != 0x80).unwrap_or(0);
+            match std::str::from_utf8(&chunk[last..]) {
+                Err(e) if e.error_len().is_none() && last > 0 => last,
+                _ => chunk.len(),
+            }
+        }
+    }
+}
+
+// Split an input made of records into files of about chunk_size bytes, reading one record at a time, so only
+// a split is held in memory. Records are kept whole: one bigger than chunk_size gets a split of its own.
+fn split_records(file_name: &path::Path, chunk_size: usize, format: InputFormat, splits: &mut Vec<Split>)
+                 -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+
+    // The header of a CSV file is repeated in every split, so each one can be parsed on its own.
+    let mut header = Vec::new();
+    let mut offset = 0;
+    if format == InputFormat::Csv {
+        offset = read_record(&mut reader, &mut header, format)?;
+    }
+
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut record = Vec::new();
+    let mut size = 0;
+    loop {
+        record.clear();
+        let record_size = read_record(&mut reader, &mut record, format)?;
+        let end_of_file = record_size == 0;
+
+        // Store the split once it has records and the next one doesn't fit in it.
+        let has_records = chunk.len() > header.len();
+        if has_records && (end_of_file || chunk.len() + record.len() > chunk_size) {
+            store_split(splits, file_name, offset, size, &chunk)?;
+            offset += size;
+            size = 0;
+            chunk.clear();
+        }
+
+        if end_of_file {
+            break;
+        }
+
+        if chunk.is_empty() {
+            chunk.extend_from_slice(&header);
+        }
+        chunk.extend_from_slice(&record);
+        size += record_size;
+    }
+
+    Ok(())
+}
+
+// Read the next record of an input into buf, with its line break. Returns the number of bytes read from the
+// input, which is 0 at the end of it. The line breaks of CSV fields in quotes don't end their record.
+fn read_record(reader: &mut impl BufRead, buf: &mut Vec<u8>, format: InputFormat) -> io::Result<u64> {
+    let start = buf.len();
+    let mut quoted = false;
+
+    loop {
+        let line_start = buf.len();
+        if reader.read_until(b'\n', buf)? == 0 {
+            break;
+        }
+
+        // Escaped quotes come in pairs, so they don't change whether the line ends in a quoted field.
+        if format == InputFormat::Csv {
+            let quotes = buf[line_start..].iter().filter(|&&byte| byte == b'"').count();
+            quoted ^= quotes % 2 == 1;
+        }
+
+        if !quoted {
+            break;
+        }
+    }
+
+    // The last record may have no line break, so it's added to keep records apart in the split.
+    let read = (buf.len() - start) as u64;
+    if read > 0 && buf.last() != Some(&b'\n') {
+        buf.push(b'\n');
+    }
 
-        num_chunks += 1;
+    Ok(read)
+}
+
+// Store the content of a split in the next map file, and add it to the splits.
+fn store_split(splits: &mut Vec<Split>, source: &path::Path, offset: u64, size: u64, content: &[u8])
+               -> io::Result<()> {
+    let file_path = map_file_name(splits.len() as i32);
+    File::create(&file_path)?.write_all(content)?;
+
+    splits.push(Split {
+        file_path: file_path.display().to_string(),
+        source: source.display().to_string(),
+        offset,
+        size,
+    });
+
+    Ok(())
+}
+
+// Store the index of the splits, one JSON object per line, so the master can tell map operations the input
+// file of their split.
+fn store_splits(splits: &[Split]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(splits_file_name())?);
+    for split in splits {
+        serde_json::to_writer(&mut file, split)?;
+        file.write_all(b"\n")?;
     }
 
-    num_chunks
+    file.flush()
+}
+
+// Load the index of the splits stored by split_data.
+pub fn load_splits() -> io::Result<Vec<Split>> {
+    let file = BufReader::new(File::open(splits_file_name())?);
+
+    serde_json::Deserializer::from_reader(file)
+        .into_iter::<Split>()
+        .map(|split| split.map_err(io::Error::from))
+        .collect()
 }
 
 // Support function to generate the name of map files.
@@ -227,6 +403,26 @@ pub fn map_file_name(id: i32) -> path::PathBuf {
     file_path
 }
 
+// Support function to generate the name of the index of the splits.
+pub fn splits_file_name() -> path::PathBuf {
+    path::Path::new(MAP_PATH).join(SPLITS_FILE)
+}
+
+// Support function to generate the name of the files with the output of a map operation for a reduce job.
+pub fn partition_file_name(id_map: i32, id_reduce: i32) -> path::PathBuf {
+    path::Path::new(REDUCE_PATH).join(reduce_name(id_map, id_reduce))
+}
+
+// Support function to generate the name of the local copies of map outputs fetched from other workers.
+pub fn fetched_partition_file_name(id_map: i32, id_reduce: i32) -> path::PathBuf {
+    path::Path::new(REDUCE_PATH).join(format!("fetched-{id_map}-{id_reduce}"))
+}
+
+// Support function to generate the name of the runs written while merging the map outputs of a reduce job.
+pub fn spill_file_name(id_reduce: i32, pass: i32, id_run: i32) -> path::PathBuf {
+    path::Path::new(REDUCE_PATH).join(format!("spill-{id_reduce}-{pass}-{id_run}"))
+}
+
 // Support function to generate the name of result files.
 // NOTE: TESTED
 pub fn result_file_name(id: i32) -> path::PathBuf {
@@ -235,6 +431,12 @@ pub fn result_file_name(id: i32) -> path::PathBuf {
     file_path
 }
 
+// Support function to generate the name of the copy of a reduce result being fetched by the master. It's
+// renamed to the result once it's complete, since the worker may have stored it in the same directory.
+pub fn fetched_result_file_name(id: i32) -> path::PathBuf {
+    path::Path::new(RESULT_PATH).join(format!("fetched-{}", id))
+}
+
 // fanInFilePath will run a goroutine that returns the path of files created during
 // splitData. These paths will be sent to remote workers so they can access the data
 // and run map operations on it.
@@ -246,7 +448,7 @@ pub fn fan_in_file_path(num_files: i32) -> Receiver<String> {
             let file_path = map_file_name(i);
 
             if let Some(file_path_str) = file_path.to_str() {
-                if let Err(_) = output_tx.send(file_path_str.to_string()).await {
+                if output_tx.send(file_path_str.to_string()).await.is_err() {
                     break;
                 }
             }
@@ -262,19 +464,18 @@ pub fn fan_in_file_path(num_files: i32) -> Receiver<String> {
 // the mapreduce framework through the one-way channel. It'll buffer data up to
 // MAP_BUFFER_SIZE (files smaller than chunkSize) and resume loading them
 // after they are read on the other side of the channle (in the mapreduce package)
-pub fn fan_in_data(num_files: i32) -> Receiver<Vec<u8>> {
+// Each input is named after the input file of its split.
+pub fn fan_in_data(splits: Vec<Split>) -> Receiver<(String, Vec<u8>)> {
     let (output_tx, output_rx) = mpsc::channel(MAP_BUFFER_SIZE);
 
     tokio::spawn(async move {
-        for i in 0..num_files {
-            let file_path = map_file_name(i);
-
-            if let Ok(file) = File::open(&file_path) {
+        for split in splits {
+            if let Ok(file) = File::open(&split.file_path) {
                 let mut reader = BufReader::new(file);
                 let mut buffer = Vec::new();
                 reader.read_to_end(&mut buffer).unwrap();
 
-                if let Err(_) = output_tx.send(buffer).await {
+                if output_tx.send((split.source, buffer)).await.is_err() {
                     break;
                 }
             }
@@ -286,46 +487,174 @@ pub fn fan_in_data(num_files: i32) -> Receiver<Vec<u8>> {
     output_rx
 }
 
-// fanOutData will run a goroutine that receive data on the one-way channel and will
-// proceed to store it in their final destination. The data will come out after the
-// reduce phase of the mapreduce model.
-pub fn fan_out_data() -> (Sender<Vec<KeyValue>>, Receiver<bool>) {
-    let (output_tx, output_rx) = mpsc::channel::<Vec<KeyValue>>(REDUCE_BUFFER_SIZE);
-    let (done_tx, done_rx) = mpsc::channel(1);
-    let mut reduce_counter = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_cluster::RunDir;
 
-    let output_rx = Mutex::new(output_rx);
+    fn read_records(input: &[u8], format: InputFormat) -> Vec<(String, u64)> {
+        let mut reader = input;
+        let mut records = Vec::new();
 
-    tokio::spawn(async move {
-        while let Some(data) = (*output_rx.lock().await).recv().await {
-            let file_path = result_file_name(reduce_counter);
-            let mut file = File::create(&file_path).expect("Error creating file");
-
-            for kv in data {
-                let json = match serde_json::to_string(&kv) {
-                    Ok(json) => json,
-                    Err(err) => {
-                        eprintln!("Error serializing data: {}", err);
-                        continue;
-                    }
-                };
-                match file.write_all(json.as_bytes()) {
-                    Ok(_) => (),
-                    Err(err) => eprintln!("Error writing to file: {}", err),
-                }
-                match file.write_all(b"\n") {
-                    Ok(_) => (),
-                    Err(err) => eprintln!("Error writing to file: {}", err),
-                }
+        loop {
+            let mut record = Vec::new();
+            let size = read_record(&mut reader, &mut record, format).unwrap();
+            if size == 0 {
+                return records;
             }
-
-            reduce_counter += 1;
+            records.push((String::from_utf8(record).unwrap(), size));
         }
+    }
 
-        if let Err(err) = done_tx.send(true).await {
-            eprintln!("Error sending to channel 'done': {}", err);
-        }
-    });
+    // Split the input, returning the content, offset and size of each split.
+    fn split(input: &str, chunk_size: usize, format: InputFormat) -> Vec<(String, u64, u64)> {
+        let input_path = path::Path::new("input.csv");
+        fs::write(input_path, input).unwrap();
+
+        let mut splits = Vec::new();
+        split_records(input_path, chunk_size, format, &mut splits).unwrap();
+
+        splits.into_iter()
+            .map(|split| (fs::read_to_string(&split.file_path).unwrap(), split.offset, split.size))
+            .collect()
+    }
+
+    // Split prose, returning the content, offset and size of each split. The content of every split must be
+    // valid UTF-8 if the input is.
+    fn split_prose(input: &str, chunk_size: usize) -> Vec<(String, u64, u64)> {
+        let input_path = path::Path::new("input.txt");
+        fs::write(input_path, input).unwrap();
+
+        let mut splits = Vec::new();
+        split_text(input_path, chunk_size, &mut splits).unwrap();
+
+        splits.into_iter()
+            .map(|split| (String::from_utf8(fs::read(&split.file_path).unwrap()).unwrap(), split.offset, split.size))
+            .collect()
+    }
+
+    #[test]
+    fn read_record_quoted_line_break() {
+        let records = read_records(b"a,\"x\ny\"\nb,c\n", InputFormat::Csv);
 
-    (output_tx, done_rx)
-}
\ No newline at end of file
+        assert_eq!(records, vec![("a,\"x\ny\"\n".to_string(), 8), ("b,c\n".to_string(), 4)]);
+    }
+
+    #[test]
+    fn read_record_escaped_quotes() {
+        // The escaped quotes of the first line leave it in a quoted field, which ends in the second one.
+        let records = read_records(b"a,\"say \"\"hi\"\"\nthere\"\nb\n", InputFormat::Csv);
+
+        assert_eq!(records, vec![("a,\"say \"\"hi\"\"\nthere\"\n".to_string(), 21), ("b\n".to_string(), 2)]);
+    }
+
+    #[test]
+    fn read_record_quotes_outside_csv() {
+        let records = read_records(b"\"a\nb\n", InputFormat::Lines);
+
+        assert_eq!(records, vec![("\"a\n".to_string(), 3), ("b\n".to_string(), 2)]);
+    }
+
+    #[test]
+    fn read_record_last_without_line_break() {
+        // The line break is added to the record, but it isn't counted as read from the input.
+        let records = read_records(b"a\nb", InputFormat::Lines);
+
+        assert_eq!(records, vec![("a\n".to_string(), 2), ("b\n".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn split_records_header_only() {
+        let _run = RunDir::enter("splits").await;
+
+        assert_eq!(split("k,v\n", 100, InputFormat::Csv), vec![]);
+    }
+
+    #[tokio::test]
+    async fn split_records_repeats_header() {
+        let _run = RunDir::enter("splits").await;
+
+        // Offsets and sizes are of the records in the input, without the header. The last record has no line
+        // break, which is added to its split.
+        let splits = split("k,v\na,1\nb,2\nc,3", 12, InputFormat::Csv);
+
+        assert_eq!(splits, vec![
+            ("k,v\na,1\nb,2\n".to_string(), 4, 8),
+            ("k,v\nc,3\n".to_string(), 12, 3),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn split_records_larger_than_chunk() {
+        let _run = RunDir::enter("splits").await;
+
+        let splits = split("k,v\na,1\nb,\"a long\nquoted value\"\nc,3\n", 12, InputFormat::Csv);
+
+        assert_eq!(splits, vec![
+            ("k,v\na,1\n".to_string(), 4, 4),
+            ("k,v\nb,\"a long\nquoted value\"\n".to_string(), 8, 24),
+            ("k,v\nc,3\n".to_string(), 32, 4),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn split_text_at_last_whitespace() {
+        let _run = RunDir::enter("splits").await;
+
+        // Splits end after their last whitespace, which stays in them.
+        let splits = split_prose("one two\nthree four", 10);
+
+        assert_eq!(splits, vec![
+            ("one two\n".to_string(), 0, 8),
+            ("three ".to_string(), 8, 6),
+            ("four".to_string(), 14, 4),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn split_text_word_larger_than_chunk() {
+        let _run = RunDir::enter("splits").await;
+
+        let splits = split_prose("abcdefghij kl", 4);
+
+        assert_eq!(splits, vec![
+            ("abcd".to_string(), 0, 4),
+            ("efgh".to_string(), 4, 4),
+            ("ij ".to_string(), 8, 3),
+            ("kl".to_string(), 11, 2),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn split_text_multibyte_character_at_edge() {
+        let _run = RunDir::enter("splits").await;
+
+        // The first chunk ends in the middle of 'é', which is left for the next split.
+        let splits = split_prose("abéd", 3);
+
+        assert_eq!(splits, vec![
+            ("ab".to_string(), 0, 2),
+            ("éd".to_string(), 2, 3),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn split_text_contiguous() {
+        let _run = RunDir::enter("splits").await;
+
+        let book = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("files/pg1342.txt");
+        let input = fs::read(&book).unwrap();
+        let mut splits = Vec::new();
+        split_text(&book, 1000, &mut splits).unwrap();
+
+        // Each split starts where the previous one ended, and has the content of the input between them.
+        let mut offset = 0;
+        for split in &splits {
+            assert_eq!(split.offset, offset);
+            assert!(split.size <= 1000);
+            assert_eq!(fs::read(&split.file_path).unwrap(), &input[offset as usize..(offset + split.size) as usize]);
+            offset += split.size;
+        }
+        assert_eq!(offset, input.len() as u64);
+    }
+}
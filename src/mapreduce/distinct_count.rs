@@ -0,0 +1,37 @@
+use crate::common::{KeyValue, RunnableTask, Task};
+use crate::word_count;
+
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeSet;
+
+pub const DISTINCT_KEY: &str = "distinct";
+
+// Different words found so far, and how many they are.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DistinctWords {
+    pub count: u64,
+    pub words: BTreeSet<String>,
+}
+
+pub fn new_task(_: &str) -> Result<Box<dyn RunnableTask>, String> {
+    Ok(Box::new(Task::new_task(map_func, shuffle_func, reduce_func).with_preprocessor(word_count::normalize)))
+}
+
+// The different words of the input are the value of a single key.
+pub fn map_func(_: &str, buffer: &[u8]) -> Vec<KeyValue<String, DistinctWords>> {
+    let words: BTreeSet<String> = word_count::split_words(buffer).into_iter().collect();
+
+    vec![KeyValue { key: DISTINCT_KEY.to_string(), value: DistinctWords { count: words.len() as u64, words } }]
+}
+
+// The words of all the inputs are joined and counted.
+pub fn reduce_func(_: &String, values: &mut dyn Iterator<Item = DistinctWords>) -> DistinctWords {
+    let words: BTreeSet<String> = values.flat_map(|value| value.words).collect();
+
+    DistinctWords { count: words.len() as u64, words }
+}
+
+// There is a single key, so every word is counted in the first reduce job.
+pub fn shuffle_func(_: &String, _: i32) -> i32 {
+    0
+}
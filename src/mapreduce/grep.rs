@@ -0,0 +1,28 @@
+use crate::common::{KeyValue, RunnableTask, Task};
+use crate::word_count;
+
+pub fn new_task(args: &str) -> Result<Box<dyn RunnableTask>, String> {
+    if args.is_empty() {
+        return Err("The grep job needs a pattern in the job arguments.".to_string());
+    }
+    let pattern = args.to_string();
+
+    let map = move |_: &str, buffer: &[u8]| map_func(&pattern, buffer);
+    Ok(Box::new(Task::new_task(map, word_count::shuffle_func, reduce_func).with_combiner(reduce_func)))
+}
+
+// Each line that contains the pattern is a key. Lines are only whole if the input is split
+// between lines (`--inputformat lines`).
+pub fn map_func(pattern: &str, buffer: &[u8]) -> Vec<KeyValue<String, u64>> {
+    let text = String::from_utf8_lossy(buffer);
+
+    text.lines()
+        .filter(|line| line.contains(pattern))
+        .map(|line| KeyValue { key: line.to_string(), value: 1 })
+        .collect()
+}
+
+// The same line may appear more than once, so they are counted.
+pub fn reduce_func(line: &String, counts: &mut dyn Iterator<Item = u64>) -> u64 {
+    word_count::reduce_func(line, counts)
+}
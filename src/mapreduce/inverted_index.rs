@@ -0,0 +1,26 @@
+use crate::common::{KeyValue, RunnableTask, Task};
+use crate::word_count;
+
+use std::collections::BTreeSet;
+
+pub fn new_task(_: &str) -> Result<Box<dyn RunnableTask>, String> {
+    Ok(Box::new(Task::new_task(map_func, word_count::shuffle_func, reduce_func)
+        .with_combiner(reduce_func)
+        .with_preprocessor(word_count::normalize)))
+}
+
+// Each word of the input is a key, and the name of the input is the value.
+pub fn map_func(name: &str, buffer: &[u8]) -> Vec<KeyValue<String, Vec<String>>> {
+    let words: BTreeSet<String> = word_count::split_words(buffer).into_iter().collect();
+
+    words.into_iter()
+        .map(|word| KeyValue { key: word, value: vec![name.to_string()] })
+        .collect()
+}
+
+// The inputs of each word are sorted, without repetitions.
+pub fn reduce_func(_: &String, names: &mut dyn Iterator<Item = Vec<String>>) -> Vec<String> {
+    let names: BTreeSet<String> = names.flatten().collect();
+
+    names.into_iter().collect()
+}
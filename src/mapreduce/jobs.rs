@@ -0,0 +1,63 @@
+use crate::common::RunnableTask;
+use crate::{distinct_count, grep, inverted_index, word_count};
+
+// Job is a MapReduce operation that can be selected by name. The master sends the name and arguments of
+// its job to the workers when they register, so they run the same one. Crates that define their own jobs
+// list them, with the built-in ones they need, and give the list to their workers.
+#[derive(Debug)]
+pub struct Job {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub new_task: fn(&str) -> Result<Box<dyn RunnableTask>, String>,   // Build the task from the job arguments
+}
+
+pub const DEFAULT_JOB: &str = "wordcount";
+
+// Built-in jobs.
+pub const JOBS: &[Job] = &[
+    Job {
+        name: "wordcount",
+        description: "Count the occurrences of each word",
+        new_task: word_count::new_task,
+    },
+    Job {
+        name: "grep",
+        description: "Count the lines that contain the pattern given in the job arguments",
+        new_task: grep::new_task,
+    },
+    Job {
+        name: "invertedindex",
+        description: "List the inputs each word appears in",
+        new_task: inverted_index::new_task,
+    },
+    Job {
+        name: "distinctcount",
+        description: "Count and list the different words",
+        new_task: distinct_count::new_task,
+    },
+];
+
+// Build the task of a built-in job, by name.
+pub fn new_task(name: &str, args: &str) -> Result<Box<dyn RunnableTask>, String> {
+    new_job_task(JOBS, name, args)
+}
+
+// Build the task of one of the jobs, by name.
+pub fn new_job_task(jobs: &[Job], name: &str, args: &str) -> Result<Box<dyn RunnableTask>, String> {
+    let job = jobs.iter()
+        .find(|job| job.name == name)
+        .ok_or_else(|| format!("Unknown job: {}. Available jobs:\n{}", name, describe_jobs(jobs)))?;
+
+    let mut task = (job.new_task)(args)?;
+    task.set_job(job.name, args);
+
+    Ok(task)
+}
+
+// One line for each job, with its name and description.
+pub fn describe_jobs(jobs: &[Job]) -> String {
+    jobs.iter()
+        .map(|job| format!("  {}: {}", job.name, job.description))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
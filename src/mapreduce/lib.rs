@@ -0,0 +1,38 @@
+// MapReduce framework. Jobs are Tasks built from map, shuffle and reduce functions, which run sequentially,
+// in a pool of threads, or in a Master and its Workers. The main, master and worker binaries are thin
+// wrappers around it, and other crates can use it to define and run their own jobs.
+
+/* Framework **********************************************************************************************************/
+pub mod common;
+pub mod data;
+pub mod jobs;
+pub mod mapreduce;
+pub mod record;
+mod merge;
+
+/* Distributed nodes **************************************************************************************************/
+pub mod master;
+pub mod worker;
+pub mod local_cluster;
+mod master_remoteworker;
+mod master_scheduler;
+
+/* Built-in jobs ******************************************************************************************************/
+pub mod word_count;
+pub mod grep;
+pub mod inverted_index;
+pub mod distinct_count;
+
+/* Command line settings of the binaries ******************************************************************************/
+pub mod cli;
+
+#[cfg(any(test, feature = "test-harness"))]
+pub mod test_cluster;
+
+/* Tonic RPC generated stubs ******************************************************************************************/
+pub mod common_rpc {
+    tonic::include_proto!("common_rpc");                        // This string must match the proto package name.
+}
+
+pub use common::{KeyValue, RunnableTask, Task};
+pub use jobs::Job;
@@ -0,0 +1,236 @@
+use clap::ArgMatches;
+use std::env;
+use std::fs::{self, File};
+use std::net::TcpListener;
+use std::ops::{Deref, DerefMut};
+use std::path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::jobs;
+
+// Directory with the logs of the jobs run in a local cluster, one directory for each job.
+pub const CLUSTER_PATH: &str = "cluster";
+
+// How often the processes of the cluster are checked, and how long workers have to exit once the job is done.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const WORKER_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Settings of the main binary that are passed on to the master, so it runs the same job.
+const MASTER_SETTINGS: &[&str] = &["reducejobs", "chunksize", "inputformat", "job", "jobargs", "format", "heartbeat",
+                                   "maxmissed"];
+
+// RunLocalCluster runs the job in a master and a number of workers, each in its own process on a free
+// localhost port, and waits for the job to be done. The nodes are the master and worker binaries, found next
+// to the running one. The output of each process is stored in a directory of the job, which is returned.
+// Nodes are stopped if the cluster fails or main is interrupted, so it must run inside a Tokio runtime, which
+// listens for the signals.
+pub fn run_local_cluster(matches: &ArgMatches, workers: usize) -> Result<path::PathBuf, String> {
+    if workers == 0 {
+        return Err("A local cluster needs at least one worker.".to_string());
+    }
+
+    let interrupted = watch_interrupts();
+    let master_binary = sibling_binary("master")?;
+    let worker_binary = sibling_binary("worker")?;
+
+    let job = matches.value_of("job").unwrap_or(jobs::DEFAULT_JOB);
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let job_dir = path::Path::new(CLUSTER_PATH).join(format!("{}-{}", job, started));
+    fs::create_dir_all(&job_dir).map_err(|err| format!("Failed to create {}: {}", job_dir.display(), err))?;
+
+    let master_port = free_port()?;
+    let mut master_args = vec!["--addr".to_string(), "localhost".to_string(), "--port".to_string(),
+                               master_port.to_string()];
+    master_args.extend(master_settings(matches));
+
+    println!("Starting master on localhost:{}.", master_port);
+    let mut master = spawn_node(&master_binary, &master_args, &job_dir.join("master.log"))?;
+
+    let mut worker_nodes = Vec::with_capacity(workers);
+    for i in 0..workers {
+        let port = free_port()?;
+        let worker_args = vec!["--addr".to_string(), "localhost".to_string(), "--port".to_string(), port.to_string(),
+                               "--master".to_string(), format!("localhost:{}", master_port)];
+
+        println!("Starting worker {} on localhost:{}.", i, port);
+        worker_nodes.push(spawn_node(&worker_binary, &worker_args, &job_dir.join(format!("worker-{}.log", i)))?);
+    }
+
+    let result = wait_for_master(&mut master, &mut worker_nodes, &interrupted);
+
+    // Workers exit once the master tells them the job is done. The ones that don't are stopped.
+    let deadline = Instant::now() + WORKER_EXIT_TIMEOUT;
+    while Instant::now() < deadline && !interrupted.load(Ordering::SeqCst)
+        && worker_nodes.iter_mut().any(|worker| matches!(worker.try_wait(), Ok(None))) {
+        sleep(POLL_INTERVAL);
+    }
+    stop_nodes(worker_nodes.iter_mut());
+
+    match result? {
+        status if status.success() => Ok(job_dir),
+        status => Err(format!("Master failed ({}). Logs are in {}", status, job_dir.display())),
+    }
+}
+
+// Wait for the master to exit. If every worker exits before it, the job can't be done, so it's stopped. It's
+// also stopped, along with the workers, if main is interrupted.
+fn wait_for_master(master: &mut Node, workers: &mut [Node], interrupted: &AtomicBool) -> Result<ExitStatus, String> {
+    loop {
+        if interrupted.load(Ordering::SeqCst) {
+            stop_nodes(std::iter::once(master).chain(workers.iter_mut()));
+            return Err("Interrupted before the job was done.".to_string());
+        }
+
+        match master.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) => (),
+            Err(err) => return Err(format!("Failed to wait for the master: {}", err)),
+        }
+
+        if workers.iter_mut().all(|worker| matches!(worker.try_wait(), Ok(Some(_)))) {
+            stop_nodes(std::iter::once(master));
+            return Err("Every worker exited before the job was done.".to_string());
+        }
+
+        sleep(POLL_INTERVAL);
+    }
+}
+
+// Node is a process of the cluster, which is killed when it's dropped if it's still running.
+struct Node(Child);
+
+impl Node {
+    fn stop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
+
+impl Deref for Node {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.0
+    }
+}
+
+impl DerefMut for Node {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.0
+    }
+}
+
+// Nodes that were started are stopped when they are dropped, so they don't outlive a failed cluster.
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Start a node of the cluster, with its output written to the log file.
+fn spawn_node(executable: &path::Path, args: &[String], log_path: &path::Path) -> Result<Node, String> {
+    let log = File::create(log_path).map_err(|err| format!("Failed to create {}: {}", log_path.display(), err))?;
+    let log_err = log.try_clone().map_err(|err| format!("Failed to open {}: {}", log_path.display(), err))?;
+
+    Command::new(executable)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(log_err)
+        .spawn()
+        .map(Node)
+        .map_err(|err| format!("Failed to start {}: {}", executable.display(), err))
+}
+
+// Kill the nodes that are still running.
+fn stop_nodes<'a>(nodes: impl Iterator<Item = &'a mut Node>) {
+    for node in nodes {
+        node.stop();
+    }
+}
+
+// Listen for the signals that interrupt main, and set the returned flag once one arrives. Main no longer exits
+// on them, so the cluster can stop its nodes first.
+fn watch_interrupts() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+
+    let flag = Arc::clone(&interrupted);
+    tokio::spawn(async move {
+        interrupt().await;
+        println!("Interrupted. Stopping the cluster...");
+        flag.store(true, Ordering::SeqCst);
+    });
+
+    interrupted
+}
+
+#[cfg(unix)]
+async fn interrupt() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn interrupt() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Path of another binary of the project, which is built in the same directory as the running one.
+fn sibling_binary(name: &str) -> Result<path::PathBuf, String> {
+    let executable = env::current_exe().map_err(|err| format!("Failed to find the executable: {}", err))?;
+    let binary = executable.with_file_name(format!("{}{}", name, env::consts::EXE_SUFFIX));
+
+    if binary.is_file() {
+        Ok(binary)
+    } else {
+        Err(format!("Failed to find the {} binary at {}. Build it with cargo build", name, binary.display()))
+    }
+}
+
+// The job settings given to the main binary, in the form the master takes them.
+fn master_settings(matches: &ArgMatches) -> Vec<String> {
+    let mut args = Vec::new();
+
+    for setting in MASTER_SETTINGS {
+        if let Some(value) = matches.value_of(setting) {
+            args.push(format!("--{}", setting));
+            args.push(value.to_string());
+        }
+    }
+
+    for file in matches.values_of("file").into_iter().flatten() {
+        args.push("--file".to_string());
+        args.push(file.to_string());
+    }
+
+    if matches.is_present("stream") {
+        args.push("--stream".to_string());
+    }
+
+    args
+}
+
+// Ask the system for a free port. It's released right away, so the node can listen on it.
+fn free_port() -> Result<u16, String> {
+    TcpListener::bind("localhost:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .map_err(|err| format!("Failed to find a free port: {}", err))
+}
@@ -1,15 +1,12 @@
-mod data;
-mod common;
-mod word_count;
-mod mapreduce;
-// mod master;
-// mod worker;
-
+use distributedrust::{cli, data, jobs, local_cluster, mapreduce};
 use tokio::runtime;
 use clap::{App, Arg};
 use std::fs;
+use std::path;
+use std::process::ExitCode;
+use std::thread;
 
-fn main() {
+fn main() -> ExitCode {
     let matches = App::new("MapReduce")
         // Run mode settings
         .arg(
@@ -17,123 +14,107 @@ fn main() {
                 .short("o")
                 .long("mode")
                 .value_name("MODE")
-                .help("Run mode: distributed or sequential")
+                .help("Run mode: distributed, sequential, parallel or local-cluster")
                 .takes_value(true)
                 .default_value("distributed"),
         )
         .arg(
-            Arg::with_name("type")
-                .short("t")
-                .long("type")
-                .value_name("TYPE")
-                .help("Node type: master or worker")
-                .takes_value(true)
-                .default_value("worker"),
-        )
-        .arg(
-            Arg::with_name("reducejobs")
-                .short("r")
-                .long("reducejobs")
+            Arg::with_name("threads")
+                .short("d")
+                .long("threads")
                 .value_name("NUM")
-                .help("Number of reduce jobs that should be run")
+                .help("Number of threads of the parallel mode (0 = one for each core)")
                 .takes_value(true)
-                .default_value("5"),
+                .default_value("0"),
         )
-        // Input data settings
         .arg(
-            Arg::with_name("file")
-                .short("f")
-                .long("file")
-                .value_name("FILE")
-                .help("File to use as input")
+            Arg::with_name("workers")
+                .short("k")
+                .long("workers")
+                .value_name("NUM")
+                .help("Number of worker processes of the local-cluster mode")
                 .takes_value(true)
-                .default_value("files/pg1342.txt"),
+                .default_value("3"),
         )
         .arg(
-            Arg::with_name("chunksize")
-                .short("c")
-                .long("chunksize")
-                .value_name("SIZE")
-                .help("Size of data chunks that should be passed to map jobs (in bytes)")
+            Arg::with_name("type")
+                .short("t")
+                .long("type")
+                .value_name("TYPE")
+                .help("Node type: master or worker")
                 .takes_value(true)
-                .default_value("102400"), // Corresponds to 100*1024
+                .default_value("worker"),
         )
+        .args(&cli::reduce_args())
+        // Input data settings
+        .args(&cli::input_args())
+        .args(&cli::input_format_args())
+        // Job settings
+        .args(&cli::job_args())
         // Network settings
-        .arg(
-            Arg::with_name("addr")
-                .short("a")
-                .long("addr")
-                .value_name("ADDRESS")
-                .help("IP address to listen on")
-                .takes_value(true)
-                .default_value("localhost"),
-        )
-        .arg(
-            Arg::with_name("port")
-                .short("p")
-                .long("port")
-                .value_name("PORT")
-                .help("TCP port to listen on")
-                .takes_value(true)
-                .default_value("5000"),
-        )
-        .arg(
-            Arg::with_name("master")
-                .short("m")
-                .long("master")
-                .value_name("MASTER")
-                .help("Master address")
-                .takes_value(true)
-                .default_value("localhost:5000"),
-        )
+        .args(&cli::network_args("5000"))
+        // Worker liveness settings
+        .args(&cli::liveness_args())
+        // Input transfer settings
+        .args(&cli::transfer_args())
+        // Intermediate file settings
+        .args(&cli::format_args())
         // Induced failure on Worker
-        .arg(
-            Arg::with_name("fail")
-                .short("n")
-                .long("fail")
-                .value_name("NUM")
-                .help("Number of operations to run before failure")
-                .takes_value(true)
-                .default_value("0"),
-        )
+        .args(&cli::failure_args())
         .get_matches();
 
     // Access the values using unwrap_or_else to provide defaults
-    let mode = matches.value_of("mode").unwrap_or_else(|| "distributed");
-    let node_type = matches.value_of("type").unwrap_or_else(|| "worker");
-    let reduce_jobs = matches
-        .value_of("reducejobs")
-        .unwrap_or_else(|| "5")
-        .parse::<i32>()
-        .unwrap(); // Parse to i32
-
-    let file = matches.value_of("file").unwrap_or_else(|| "files/pg1342.txt");
-    let chunk_size = matches
-        .value_of("chunksize")
-        .unwrap_or_else(|| "102400")
-        .parse::<usize>()
-        .unwrap(); // Parse to usize
-
-    let addr = matches.value_of("addr").unwrap_or_else(|| "localhost");
-    let port = matches
-        .value_of("port")
-        .unwrap_or_else(|| "5000")
-        .parse::<u16>()
-        .unwrap(); // Parse to u16
-
-    let master = matches.value_of("master").unwrap_or_else(|| "localhost:5000");
-
-    let n_ops = matches
-        .value_of("fail")
-        .unwrap_or_else(|| "0")
-        .parse::<i32>()
-        .unwrap(); // Parse to i32
+    let mode = matches.value_of("mode").unwrap_or("distributed");
+    let node_type = matches.value_of("type").unwrap_or("worker");
+    let threads = match matches.value_of("threads").unwrap_or("0").parse::<usize>().unwrap() {
+        0 => thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1),
+        threads => threads,
+    };
+    let workers = matches.value_of("workers").unwrap_or("3").parse::<usize>().unwrap();
+    let reduce_jobs = cli::reduce_jobs(&matches);
 
+    let inputs = cli::inputs(&matches);
+    let chunk_size = cli::chunk_size(&matches);
+    let input_format = match cli::input_format(&matches) {
+        Ok(input_format) => input_format,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let hostname = cli::hostname(&matches);
+    let master = cli::master_hostname(&matches);
+    let (heartbeat_interval, max_missed_heartbeats) = cli::liveness(&matches);
+    let stream_inputs = cli::stream_inputs(&matches);
+    let format = match cli::format(&matches) {
+        Ok(format) => format,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let (n_ops, fail_mode) = match cli::failure(&matches) {
+        Ok(failure) => failure,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let _ = fs::create_dir(data::MAP_PATH);
     let _ = fs::create_dir(data::REDUCE_PATH);
     let _ = fs::create_dir(data::RESULT_PATH);
 
-    let mut task = common::Task::new_task(word_count::map_func, word_count::shuffle_func, word_count::reduce_func);
-    task.num_reduce_jobs = reduce_jobs;    
+    let mut task = match cli::job_task(&matches) {
+        Ok(task) => task,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    task.set_num_reduce_jobs(reduce_jobs);
+    task.set_format(format);
 
     let rt = runtime::Runtime::new().unwrap();
 
@@ -141,6 +122,14 @@ fn main() {
     
     match mode {
         "sequential" => {
+            let files = match data::input_files(&inputs) {
+                Ok(files) => files,
+                Err(err) => {
+                    println!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+
             rt.block_on(async {
                 if let Err(err) = data::remove_contents(data::MAP_PATH) {
                     eprintln!("Error removing contents: {}", err);
@@ -149,28 +138,75 @@ fn main() {
                     eprintln!("Error removing contents: {}", err);
                 }
 
-                let num_files = data::split_data(file, chunk_size);
+                let splits = data::split_data(&files, chunk_size, input_format);
 
-                let fan_in = data::fan_in_data(num_files as i32);
-                let (fan_out, mut wait_for_it) = data::fan_out_data();
+                let fan_in = data::fan_in_data(splits);
+                *task.input_chan() = fan_in;
 
-                task.input_chan = fan_in;
-
-                mapreduce::run_sequential(&mut task, fan_out).await;
-                
-                // Wait for the output channel to close
-                wait_for_it.recv().await.unwrap();
+                mapreduce::run_sequential(task.as_mut()).await;
             });
+
+            ExitCode::SUCCESS
+        },
+        "parallel" => {
+            let files = match data::input_files(&inputs) {
+                Ok(files) => files,
+                Err(err) => {
+                    println!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            if let Err(err) = data::remove_contents(data::MAP_PATH) {
+                eprintln!("Error removing contents: {}", err);
+            }
+            if let Err(err) = data::remove_contents(data::RESULT_PATH) {
+                eprintln!("Error removing contents: {}", err);
+            }
+
+            let splits = data::split_data(&files, chunk_size, input_format);
+
+            // Inputs are read in the runtime, while the operations run in their own threads.
+            let _runtime = rt.enter();
+            *task.input_chan() = data::fan_in_data(splits);
+
+            mapreduce::run_parallel(task.as_mut(), threads);
+
+            ExitCode::SUCCESS
+        },
+        "local-cluster" => {
+            println!("Workers: {}", workers);
+
+            // The cluster listens for interrupts in the runtime, so it can stop its nodes.
+            let _runtime = rt.enter();
+            match local_cluster::run_local_cluster(&matches, workers) {
+                Ok(job_dir) => {
+                    println!("Result: {}", path::Path::new(data::RESULT_PATH).join("result-final.txt").display());
+                    println!("Logs: {}", job_dir.display());
+                    ExitCode::SUCCESS
+                }
+                Err(err) => {
+                    println!("{}", err);
+                    ExitCode::FAILURE
+                }
+            }
         },
         "distributed" => match node_type {
             "master" => {
                 println!("Node type: {}", node_type);
                 println!("Reduce jobs: {}", reduce_jobs);
-                println!("Address: {}", addr);
-                println!("Port: {}", port);
-                println!("File: {}", file);
+                println!("Hostname: {}", hostname);
+                println!("Files: {}", inputs.join(", "));
                 println!("Chunk size: {}", chunk_size);
 
+                let files = match data::input_files(&inputs) {
+                    Ok(files) => files,
+                    Err(err) => {
+                        println!("{}", err);
+                        return ExitCode::FAILURE;
+                    }
+                };
+
                 if let Err(err) = data::remove_contents(data::MAP_PATH) {
                     eprintln!("Error removing contents: {}", err);
                 }
@@ -178,34 +214,50 @@ fn main() {
                     eprintln!("Error removing contents: {}", err);
                 }
 
-                let hostname = format!("{}:{}", addr, port);
+                let splits = data::split_data(&files, chunk_size, input_format);
 
-                let num_files = data::split_data(file, chunk_size);
+                let result = rt.block_on(async {
+                    let fan_in = data::fan_in_file_path(splits.len() as i32);
+                    *task.input_file_path_chan() = fan_in;
 
-                let fan_in = data::fan_in_file_path(num_files as i32);
-                task.input_file_path_chan = fan_in;
-                
-                // This is commented out because we couldn't get it to work
-                // mapreduce::run_master(&task, hostname);
+                    mapreduce::run_master(task.as_mut(), hostname, stream_inputs, heartbeat_interval,
+                                          max_missed_heartbeats).await
+                });
+
+                match result {
+                    Ok(()) => ExitCode::SUCCESS,
+                    Err(err) => {
+                        println!("{}", err);
+                        ExitCode::FAILURE
+                    }
+                }
             }
             "worker" => {
                 println!("Node type: {}", node_type);
-                println!("Address: {}", addr);
-                println!("Port: {}", port);
+                println!("Hostname: {}", hostname);
                 println!("Master: {}", master);
 
                 if n_ops > 0 {
-                    println!("Induced failure");
+                    println!("Induced failure ({:?})", fail_mode);
                     println!("After {} operations.", n_ops);
                 }
 
-                let hostname = format!("{}:{}", addr, port);
-
-                // This is commented out because we couldn't get it to work
-                // mapreduce::run_worker(&task, hostname, master, n_ops);
+                match rt.block_on(mapreduce::run_worker(hostname, master, jobs::JOBS, n_ops, fail_mode)) {
+                    Ok(()) => ExitCode::SUCCESS,
+                    Err(err) => {
+                        println!("{}", err);
+                        ExitCode::FAILURE
+                    }
+                }
+            }
+            _ => {
+                println!("Invalid node type: {}", node_type);
+                ExitCode::FAILURE
             }
-            _ => println!("Invalid node type: {}", node_type),
         },
-        _ => println!("Invalid mode: {}", mode),
+        _ => {
+            println!("Invalid mode: {}", mode);
+            ExitCode::FAILURE
+        }
     }
 }
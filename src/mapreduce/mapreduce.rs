@@ -1,7 +1,31 @@
 use std::fs;
-
-use crate::common::{KeyValue, Task};
-use crate::data::{load_local, merge_map_local, remove_contents, store_local, REDUCE_PATH};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
+
+use crate::common::RunnableTask;
+use crate::common_rpc::register_server::RegisterServer;
+use crate::common_rpc::runner_client::RunnerClient;
+use crate::common_rpc::runner_server::RunnerServer;
+use crate::common_rpc::EmptyMessage;
+use crate::data::{fan_reduce_file_path, load_splits, merge_reduce_local, partition_file_name, remove_contents, REDUCE_PATH,
+                  RESULT_PATH};
+use crate::jobs::Job;
+use crate::master::{Master, MasterConfig};
+use crate::master_scheduler::{RUN_MAP, RUN_REDUCE};
+use crate::worker::{FailMode, RegisterError, Worker};
+
+// Time a worker waits before trying to register with the master again.
+const REGISTER_RETRY_DURATION: Duration = Duration::from_secs(2);
+
+// Largest request a worker accepts, since map operations may carry the content of their input.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
 
 // RunSequential will ensure that map and reduce function runs in
 // a single-core linearly. The Task is passed from the calling package
@@ -10,9 +34,9 @@ use crate::data::{load_local, merge_map_local, remove_contents, store_local, RED
 // Notice that this implementation will store data locally. In the distributed
 // version of mapreduce it's common to store the data in the same worker that computed
 // it and just pass a reference to reduce jobs so they can go grab it.
-pub async fn run_sequential(task: &mut Task, output_chan_tx: tokio::sync::mpsc::Sender<Vec<KeyValue>>) {
+// The result of each reduce job is stored in the same files used by the distributed mode.
+pub async fn run_sequential(task: &mut dyn RunnableTask) {
     let mut map_counter = 0;
-    let mut map_result;
 
     println!("Running RunSequential...");
 
@@ -20,232 +44,236 @@ pub async fn run_sequential(task: &mut Task, output_chan_tx: tokio::sync::mpsc::
     let _ = fs::create_dir(REDUCE_PATH);
     let _ = remove_contents(REDUCE_PATH);
 
-    while let Some(v) = task.input_chan.recv().await {
-        map_result = (task.map)(&v);
-        let _ = store_local(task, map_counter, &map_result);
+    while let Some((name, v)) = task.input_chan().recv().await {
+        let _ = task.run_map(map_counter, &name, &v);
         map_counter += 1;
     }
 
-    let _ = merge_map_local(task, map_counter);
-
-    for r in 0..task.num_reduce_jobs {
-        if let Ok(mut data) = load_local(r) {
-            let reduced_data = (task.reduce)(&mut data);
-            if let Err(_) = output_chan_tx.send(reduced_data.to_vec()).await {
-                println!("receiver dropped");
-                return;
-            }
+    // Every reduce job merges its partition of the output of each map operation.
+    for r in 0..task.num_reduce_jobs() {
+        let runs = (0..map_counter).map(|m| partition_file_name(m, r)).collect();
+        if let Err(err) = task.run_reduce(r, runs) {
+            println!("Reduce {} failed. Error: {:?}", r, err);
         }
     }
-    // Close the output channel
-    drop(output_chan_tx);
 }
 
-// NOTE: the rest of the code is not used, as it'd break the compilation
+// RunParallel runs the task like RunSequential, but in a pool of threads: map operations run as their inputs
+// are received, and then the reduce jobs. Map operations are numbered in the order of their inputs, so each
+// reduce job merges the same runs in the same order, and the result is the same as in sequential mode.
+// It blocks until the task is done, so the inputs must be sent from another thread, such as a runtime's.
+pub fn run_parallel(task: &mut dyn RunnableTask, threads: usize) {
+    println!("Running RunParallel with {} threads...", threads);
+
+    // Create or clear the REDUCE_PATH directory
+    let _ = fs::create_dir(REDUCE_PATH);
+    let _ = remove_contents(REDUCE_PATH);
+
+    // Threads take turns to receive an input, and number it as they do.
+    let inputs = Mutex::new((std::mem::replace(task.input_chan(), mpsc::channel(1).1), 0));
+    let task: &dyn RunnableTask = task;
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let (id_map, name, input) = {
+                    let mut inputs = inputs.lock().unwrap();
+                    let (name, input) = match inputs.0.blocking_recv() {
+                        Some(input) => input,
+                        None => break,
+                    };
+                    inputs.1 += 1;
+                    (inputs.1 - 1, name, input)
+                };
+
+                let _ = task.run_map(id_map, &name, &input);
+            });
+        }
+    });
+    let map_counter = inputs.into_inner().unwrap().1;
+
+    // Every reduce job merges its partition of the output of each map operation.
+    let next_reduce = AtomicI32::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let r = next_reduce.fetch_add(1, Ordering::SeqCst);
+                if r >= task.num_reduce_jobs() {
+                    break;
+                }
+
+                let runs = (0..map_counter).map(|m| partition_file_name(m, r)).collect();
+                if let Err(err) = task.run_reduce(r, runs) {
+                    println!("Reduce {} failed. Error: {:?}", r, err);
+                }
+            });
+        }
+    });
+}
 
 // RunMaster will start a master node on the map reduce operations.
 // In the distributed model, a Master should serve multiple workers and distribute
 // the operations to be executed in order to complete the task.
 // 	- task: the Task object that contains the mapreduce operation.
 //  - hostname: the tcp/ip address on which it will listen for connections.
-// pub async fn run_master(task: &Task, hostname: String) {
-//     let mut err: Option<io::Error> = None;
-//     let master: Arc<Mutex<Master>> = Arc::new(Mutex::new(Master::new(&hostname)));
-
-//     println!("Running Master on {}", hostname);
-
-//     // Create a reduce directory to store intermediate reduce files.
-//     let _ = fs::create_dir(REDUCE_PATH);
-//     let _ = remove_contents(REDUCE_PATH);
-
-//     {
-//         let mut master = master.lock().unwrap();
-//         master.task = Some(task.clone());
-//         master.rpc_server = Some(rpc::Server::new());
-
-//         if let Some(ref mut rpc_server) = master.rpc_server {
-//             rpc_server.register(master.clone());
-
-//             // Handle errors here
-//             if let Err(e) = err {
-//                 println!("Failed to register RPC server. Error: {:?}", e);
-//                 return;
-//             }
-//         }
-
-//         let listener = match TcpListener::bind(&master.address) {
-//             Ok(listener) => listener,
-//             Err(e) => {
-//                 println!("Failed to start TCP server. Error: {:?}", e);
-//                 return;
-//             }
-//         };
-
-//         master.listener = Some(listener.try_clone().unwrap());
-//     }
-
-//     let master_clone = Arc::clone(&master);
-
-//     // Start MapReduce Operation
-//     tokio::spawn(async move {
-//         let master = master_clone.lock().unwrap();
-//         master.accept_multiple_connections();
-//     });
-
-//     let master_clone = Arc::clone(&master);
-//     tokio::spawn(async move {
-//         let master = master_clone.lock().unwrap();
-//         master.handle_failing_workers();
-//     });
-
-//     // Schedule map operations
-//     let map_operations = {
-//         let master = master.lock().unwrap();
-//         master.schedule(task, "Worker.RunMap", task.input_file_path_chan.clone())
-//     };
-
-//     // Merge the result of multiple map operations with the same reduceId into a single file
-//     merge_map_local(task, map_operations);
-
-//     // Schedule reduce operations
-//     let reduce_file_path_chan = fan_reduce_file_path(task.num_reduce_jobs);
-//     let reduce_operations = {
-//         let master = master.lock().unwrap();
-//         master.schedule(task, "Worker.RunReduce", reduce_file_path_chan)
-//     };
-
-//     merge_reduce_local(reduce_operations);
-
-//     println!("Closing Remote Workers.");
-//     {
-//         let master = master.lock().unwrap();
-//         for worker in &master.workers {
-//             if let Err(e) = worker.call_remote_worker("Worker.Done", &(), &()) {
-//                 println!("Failed to close Remote Worker. Error: {:?}", e);
-//             }
-//         }
-//     }
-
-//     println!("Done.");
-// }
-
-// // RunWorker will run a instance of a worker. It'll initialize and then try to register with
-// // master.
-// // Induced failures:
-// // -> nOps = number of operations to run before failure (0 = no failure)
-// pub fn run_worker(task: &Task, hostname: String, master_hostname: String, n_ops: usize) {
-//     let mut err: Option<io::Error> = None;
-//     let mut worker = Worker::new();
-//     let retry_duration = Duration::from_secs(2);
-
-//     println!("Running Worker on {}", hostname);
-
-//     // Create a reduce directory to store intermediate reduce files.
-//     let _ = fs::create_dir(REDUCE_PATH);
-
-//     worker.hostname = hostname;
-//     worker.master_hostname = master_hostname;
-//     worker.task = Some(task.clone());
-//     worker.done = Some(mpsc::channel());
-
-//     // Should induce a failure
-//     if n_ops > 0 {
-//         worker.task_counter = 0;
-//         worker.n_ops = n_ops;
-//     }
-
-//     let rpc_server = Arc::new(Mutex::new(rpc::Server::new()));
-//     {
-//         let mut rpc_server = rpc_server.lock().unwrap();
-//         rpc_server.register(worker.clone());
-
-//         // Handle errors here
-//         if let Err(e) = err {
-//             println!("Failed to register RPC server. Error: {:?}", e);
-//             return;
-//         }
-
-//         let listener = match TcpListener::bind(&worker.hostname) {
-//             Ok(listener) => listener,
-//             Err(e) => {
-//                 println!("Starting RPC listener failed. Error: {:?}", e);
-//                 return;
-//             }
-//         };
-
-//         worker.listener = Some(listener.try_clone().unwrap());
-//         worker.rpc_server = Some(rpc_server.clone());
-//     }
-
-//     let worker_clone = worker.clone();
-//     let rpc_server_clone = rpc_server.clone();
-
-//     // Ensure the listener is closed when the function exits
-//     let _guard = OnDropGuard(|| {
-//         if let Some(listener) = worker_clone.listener {
-//             let _ = listener.close();
-//         }
-//     });
-
-//     let retry_duration_clone = retry_duration.clone();
-//     tokio::spawn(async move {
-//         let mut worker = worker_clone;
-//         let mut rpc_server = rpc_server_clone.lock().unwrap();
-
-//         for _ in 0..n_ops {
-//             worker.task_counter = 0;
-
-//             // Rest of the loop body
-//             rpc_server.register(worker.clone());
-
-//             // Handle errors here
-//             if let Err(e) = err {
-//                 println!("Failed to register RPC server. Error: {:?}", e);
-//                 return;
-//             }
-
-//             let listener = match TcpListener::bind(&worker.hostname) {
-//                 Ok(listener) => listener,
-//                 Err(e) => {
-//                     println!("Starting RPC listener failed. Error: {:?}", e);
-//                     return;
-//                 }
-//             };
-
-//             worker.listener = Some(listener.try_clone().unwrap());
-//             worker.rpc_server = Some(rpc_server.clone());
-
-//             let _guard = OnDropGuard(|| {
-//                 if let Some(listener) = worker.listener {
-//                     let _ = listener.close();
-//                 }
-//             });
-
-//             // Rest of the thread body
-//         }
-//     });
-
-//     let worker_clone = Arc::new(Mutex::new(worker));
-//     let retry_duration_clone = retry_duration.clone();
-//     tokio::spawn(async move {
-//         let mut worker = worker_clone.lock().unwrap();
-
-//         loop {
-//             if let Err(_) = worker.register() {
-//                 println!(
-//                     "Registration failed. Retrying in {:?} seconds...",
-//                     retry_duration_clone
-//                 );
-//                 tokio::time::sleep(retry_duration_clone).await;
-//             } else {
-//                 break;
-//             }
-//         }
-
-//         worker.accept_multiple_connections();
-//     });
-
-//     // Wait for the worker to finish
-//     let (_, receiver) = worker.done.unwrap();
-//     receiver.recv().unwrap();
-// }
+//  - stream_inputs: send the content of map inputs to the workers, instead of their paths.
+//  - heartbeat_interval: how often workers should signal they are alive.
+//  - max_missed_heartbeats: number of heartbeats a worker can miss before it's considered dead.
+// It returns an error if the job could not be done.
+pub async fn run_master(task: &mut dyn RunnableTask, hostname: String, stream_inputs: bool, heartbeat_interval: Duration,
+                        max_missed_heartbeats: u32) -> Result<(), String> {
+    let address = match resolve_address(&hostname) {
+        Some(address) => address,
+        None => return Err(format!("Failed to resolve address {}.", hostname)),
+    };
+
+    // Bind the listener first, so a master that can't listen fails instead of waiting for workers forever.
+    let incoming = TcpIncoming::new(address, true, None)
+        .map_err(|err| format!("Starting RPC listener on {} failed. Error: {}", hostname, err))?;
+
+    println!("Running Master on {}", hostname);
+
+    // Create a reduce directory to store intermediate reduce files.
+    let _ = fs::create_dir(REDUCE_PATH);
+    let _ = remove_contents(REDUCE_PATH);
+
+    let config = MasterConfig {
+        reduce_jobs: task.num_reduce_jobs(),
+        job: task.job().to_string(),
+        job_args: task.job_args().to_string(),
+        format: task.format(),
+        stream_inputs,
+        heartbeat_interval,
+        max_missed_heartbeats,
+    };
+    let master = Arc::new(Master::new_master(address, config));
+
+    // Accept worker registrations.
+    let server = tokio::spawn(
+        Server::builder()
+            .add_service(RegisterServer::from_arc(Arc::clone(&master)))
+            .serve_with_incoming(incoming),
+    );
+
+    // Remove workers that failed from the list of workers.
+    let master_clone = Arc::clone(&master);
+    let failing_workers = tokio::spawn(async move {
+        master_clone.handle_failing_workers().await;
+    });
+
+    // Mark workers that stopped sending heartbeats as dead.
+    let master_clone = Arc::clone(&master);
+    let monitor_workers = tokio::spawn(async move {
+        master_clone.monitor_workers().await;
+    });
+
+    let result = run_operations(&master, task).await;
+
+    println!("Closing Remote Workers.");
+    let workers = master.workers.lock().unwrap().clone();
+    for worker in workers {
+        let done = async {
+            let mut run_client = RunnerClient::connect(format!("http://{}", worker.hostname)).await?;
+            run_client.done(tonic::Request::new(EmptyMessage {})).await?;
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+
+        if let Err(err) = done.await {
+            println!("Failed to close Remote Worker {}. Error: {}", worker.id, err);
+        }
+    }
+
+    monitor_workers.abort();
+    failing_workers.abort();
+    server.abort();
+
+    println!("Done.");
+    result.map_err(|err| format!("MapReduce failed. Error: {}", err))
+}
+
+// Schedule the map and reduce operations of the task, merging the results of the reduce operations.
+async fn run_operations(master: &Arc<Master>, task: &mut dyn RunnableTask) -> Result<(), String> {
+    // Map operations are given the input file of their split, from the index stored along with the splits.
+    match load_splits() {
+        Ok(splits) => {
+            *master.sources.lock().unwrap() = splits.into_iter()
+                .map(|split| (split.file_path, split.source))
+                .collect();
+        }
+        Err(err) => println!("Failed to load the index of splits, map inputs are named after their files. Error: {}", err),
+    }
+
+    // Schedule map operations
+    let map_operations = master.schedule(RUN_MAP, task.input_file_path_chan()).await?;
+
+    // Map outputs stay in the workers that computed them. Reducers fetch their partition from each of them.
+    *master.map_operations.lock().unwrap() = map_operations;
+
+    // Schedule reduce operations
+    let mut reduce_file_path_chan = fan_reduce_file_path(task.num_reduce_jobs());
+    let reduce_operations = master.schedule(RUN_REDUCE, &mut reduce_file_path_chan).await?;
+
+    merge_reduce_local(reduce_operations).map_err(|err| format!("Failed to merge reduce results: {}", err))
+}
+
+// Resolve a hostname such as localhost:5000 into the socket address the master listens on.
+fn resolve_address(hostname: &str) -> Option<std::net::SocketAddr> {
+    hostname.to_socket_addrs().ok()?.next()
+}
+
+// RunWorker will run a instance of a worker. It'll initialize and then try to register with
+// master, which tells it the job to run, out of jobs (such as jobs::JOBS, the built-in ones).
+// Induced failures:
+// -> n_ops = number of operations to run before failure (0 = no failure)
+// -> fail_mode = how the worker fails once it runs n_ops operations
+// It returns an error if the worker can't run, such as when it can't load the job of the master.
+pub async fn run_worker(hostname: String, master_hostname: String, jobs: &'static [Job], n_ops: i32,
+                        fail_mode: FailMode) -> Result<(), String> {
+    let address = match resolve_address(&hostname) {
+        Some(address) => address,
+        None => return Err(format!("Failed to resolve address {}.", hostname)),
+    };
+
+    println!("Running Worker on {}", hostname);
+
+    // Create a reduce directory to store intermediate reduce files, and a result directory for the
+    // result of reduce operations.
+    let _ = fs::create_dir(REDUCE_PATH);
+    let _ = fs::create_dir(RESULT_PATH);
+
+    // Bind the listener before registering, since the master may call the worker as soon as it registers.
+    let incoming = TcpIncoming::new(address, true, None)
+        .map_err(|err| format!("Starting RPC listener failed. Error: {}", err))?;
+
+    let mut worker = Worker::new_worker(0, hostname, master_hostname, jobs, n_ops, fail_mode);
+    loop {
+        match worker.register().await {
+            Ok(()) => break,
+            Err(RegisterError::Unsupported(err)) => return Err(format!("Worker can't run the job. Error: {}", err)),
+            Err(RegisterError::Failed(_)) => {
+                println!("Registration failed. Retrying in {:?}...", REGISTER_RETRY_DURATION);
+                tokio::time::sleep(REGISTER_RETRY_DURATION).await;
+            }
+        }
+    }
+    let worker = Arc::new(worker);
+
+    // Signal the master the worker is alive while it's running.
+    let worker_clone = Arc::clone(&worker);
+    let heartbeat = tokio::spawn(async move {
+        worker_clone.heartbeat().await;
+    });
+
+    // Serve the master until it signals the worker is done.
+    let server = Server::builder()
+        .add_service(RunnerServer::from_arc(Arc::clone(&worker)).max_decoding_message_size(MAX_MESSAGE_SIZE))
+        .serve_with_incoming_shutdown(incoming, worker.done.notified())
+        .await;
+
+    heartbeat.abort();
+
+    server.map_err(|err| format!("RPC server failed. Error: {}", err))?;
+
+    println!("Done.");
+    Ok(())
+}
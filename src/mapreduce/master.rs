@@ -1,43 +1,80 @@
 /* General Imports ****************************************************************************************************/
-mod common;
-mod word_count;
-mod master_remoteworker;
+use tonic::{Request, Response, Status};
+use tokio::sync::mpsc::{channel, Sender, Receiver};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use tonic::{transport::Server, Request, Response, Status};
-use tokio::sync::mpsc::{Sender, channel};
-use std::sync::{Arc, Mutex};
+use crate::common;
+use crate::master_remoteworker::{self, WorkerStatus};
+use crate::record::Format;
 
 /* Tonic RPC generated stubs ******************************************************************************************/
-use common_rpc::register_server::{Register, RegisterServer};    // Master is the server in the register service.
-use common_rpc::runner_client::RunnerClient;                    // Master is the client in the runner service.
-                                                                // Client can be used without direct implementation.
-use common_rpc::{RegisterArgs, RegisterReply, RunArgs};         // Import message types.
-
-pub mod common_rpc {
-    tonic::include_proto!("common_rpc");                        // This string must match the proto package name.
-}
+use crate::common_rpc::register_server::Register;               // Master is the server in the register service.
+use crate::common_rpc::{RegisterArgs, RegisterReply, HeartbeatArgs, EmptyMessage};   // Import message types.
 
 /* Basic Definitions **************************************************************************************************/
 const IDLE_WORKER_BUFFER: usize = 100;
 const FAILED_WORKER_BUFFER: usize = 100;
 const RETRY_OPERATION_BUFFER: usize = 100;
 
+// MapOutput is the output of a completed map operation, stored locally in the worker that ran it.
+// Reducers fetch their partition of it from that worker.
+#[derive(Debug, Clone)]
+pub struct MapOutput {
+    pub hostname: String,
+    pub operation: common::Operation,
+}
+
+// MasterConfig is the job run by the master, and the settings it runs it with.
+#[derive(Debug, Clone)]
+pub struct MasterConfig {
+    pub reduce_jobs: i32,
+    pub job: String,
+    pub job_args: String,
+    pub format: Format,
+    pub stream_inputs: bool,
+    pub heartbeat_interval: Duration,
+    pub max_missed_heartbeats: u32,
+}
+
 #[derive(Debug)]
 pub struct Master {
     // Task
-    task: Arc<Mutex<common::Task>>,
+    pub reduce_jobs: i32,
+    pub job: String,                    // Name of the job, sent to workers so they load the same one
+    pub job_args: String,
+    pub format: Format,                 // Format of the map outputs, sent to workers with the job
+    pub stream_inputs: bool,            // Send the content of map inputs instead of their paths
 
     // Network
-    address: std::net::SocketAddr,
+    pub address: std::net::SocketAddr,
+
+    // Liveness
+    pub heartbeat_interval: Duration,
+    pub max_missed_heartbeats: u32,     // Workers that miss more heartbeats than this are considered dead
 
     // Sender Channels
-    idle_tx: Sender<master_remoteworker::RemoteWorker>,
-    failed_tx: Sender<master_remoteworker::RemoteWorker>,
-    retry_operation_tx: Sender<common::Operation>,
+    pub idle_tx: Sender<master_remoteworker::RemoteWorker>,
+    pub failed_tx: Sender<master_remoteworker::RemoteWorker>,
+    pub retry_operation_tx: Sender<common::Operation>,
+
+    // Receiver Channels
+    pub idle_rx: tokio::sync::Mutex<Receiver<master_remoteworker::RemoteWorker>>,
+    pub failed_rx: tokio::sync::Mutex<Receiver<master_remoteworker::RemoteWorker>>,
+    pub retry_operation_rx: tokio::sync::Mutex<Receiver<common::Operation>>,
 
     // Workers handling
-    workers: Mutex<Vec<master_remoteworker::RemoteWorker>>,
-    total_workers: Mutex<usize>, // Used to generate unique ids for new workers
+    pub workers: Mutex<Vec<master_remoteworker::RemoteWorker>>,
+    pub failed_workers: Mutex<Vec<master_remoteworker::RemoteWorker>>,
+    pub total_workers: Mutex<usize>, // Used to generate unique ids for new workers
+
+    // Map inputs
+    pub sources: Mutex<HashMap<String, String>>,   // Input file each map input was split from, by path
+
+    // Map outputs
+    pub map_operations: Mutex<i32>,                 // Number of map operations, once the map phase is over
+    pub map_outputs: Mutex<HashMap<i32, MapOutput>>,  // Completed map operations, by id
 }
 
 /* Master RPCs ********************************************************************************************************/
@@ -49,118 +86,213 @@ impl Register for Master {
     ) -> Result<Response<RegisterReply>, Status> {              // Results should have RegisterReply type.
         let args = request.into_inner();                        // Unpack request since its fields are private.
 
-        // Get the mutex for the workers.
-        let mut workers = self.workers.lock().unwrap();
-        let mut worker_count = self.total_workers.lock().unwrap();
+        // Workers that don't know the job can't run any operation of it, so they are not scheduled.
+        if !args.jobs.contains(&self.job) {
+            println!("Rejecting worker with hostname {}, which can't run job {}.", args.worker_hostname, self.job);
+            return Err(Status::failed_precondition(format!("Job {} is not one of the jobs of the worker.", self.job)));
+        }
 
-        println!("Registering worker {} with hostname {}.", *worker_count, args.worker_hostname); 
+        // Get the mutex for the workers, and create the worker while holding it. The lock must be
+        // released before awaiting on the idle channel.
+        let new_worker = {
+            let mut workers = self.workers.lock().unwrap();
+            let mut worker_count = self.total_workers.lock().unwrap();
 
-        // Create the worker and push it into the worker list. Also increase the count.
-        let new_worker = master_remoteworker::RemoteWorker::new_worker(*worker_count, 
-                                                                                     args.worker_hostname);
-        let new_worker_clone = new_worker.clone();
-        (*workers).push(new_worker);
-        *worker_count += 1;
+            println!("Registering worker {} with hostname {}.", *worker_count, args.worker_hostname);
+
+            // Create the worker and push it into the worker list. Also increase the count.
+            let new_worker = master_remoteworker::RemoteWorker::new_worker(*worker_count, args.worker_hostname);
+            workers.push(new_worker.clone());
+            *worker_count += 1;
+
+            new_worker
+        };
+        let worker_id = new_worker.id as i32;
 
         // Signal the idleWorker channel about the new worker.
-        let idle_tx = self.idle_tx.clone();
-        
-        tokio::spawn(async move {
-            idle_tx.send(new_worker_clone).await.expect("Failed to send new worker to idle channel.");
-        });
+        self.idle_tx.send(new_worker).await
+            .map_err(|_| Status::unavailable("Master is no longer accepting workers."))?;
 
-        // Respond to caller with worker number and reduce jobs.
-        Ok(Response::new(common_rpc::RegisterReply {
-            worker_id: {*worker_count - 1} as i32,
-            reduce_jobs: {1},
+        // Respond to caller with worker number, reduce jobs and the job to run.
+        Ok(Response::new(RegisterReply {
+            worker_id,
+            reduce_jobs: self.reduce_jobs,
+            heartbeat_interval: self.heartbeat_interval.as_millis() as i32,
+            job: self.job.clone(),
+            job_args: self.job_args.clone(),
+            format: self.format.name().to_string(),
         }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatArgs>,
+    ) -> Result<Response<EmptyMessage>, Status> {
+        let args = request.into_inner();
+
+        // Update the last time the worker was seen. Workers that were already removed are not brought back.
+        let mut workers = self.workers.lock().unwrap();
+        match workers.iter_mut().find(|worker| worker.id as i32 == args.worker_id) {
+            Some(worker) => {
+                worker.last_seen = Instant::now();
+
+                Ok(Response::new(EmptyMessage {}))
+            }
 
-        // Mutex is released automatically once the variable goes out of scope
+            None => Err(Status::not_found(format!("Worker {} is not registered.", args.worker_id))),
+        }
     }
 }
 
 /* Master Implementation **********************************************************************************************/
 impl Master {
-    // Construct a new Master struct
-    fn new_master(address: std::net::SocketAddr, 
-                  idle_tx: Sender<master_remoteworker::RemoteWorker>,
-                  failed_tx: Sender<master_remoteworker::RemoteWorker>,
-                  retry_operation_tx: Sender<common::Operation>,) 
-                  -> Master {
-        let master = Master {
+    // Construct a new Master struct, along with the channels for idle and failed workers, as well as
+    // fault tolerance.
+    pub fn new_master(address: std::net::SocketAddr, config: MasterConfig) -> Master {
+        let (idle_tx, idle_rx) = channel(IDLE_WORKER_BUFFER);
+        let (failed_tx, failed_rx) = channel(FAILED_WORKER_BUFFER);
+        let (retry_operation_tx, retry_operation_rx) = channel(RETRY_OPERATION_BUFFER);
+
+        Master {
             // Task
-            task: Arc::new(Mutex::new(common::Task::new_task(word_count::map_func, word_count::shuffle_func, word_count::reduce_func))),
-            
+            reduce_jobs: config.reduce_jobs,
+            job: config.job,
+            job_args: config.job_args,
+            format: config.format,
+            stream_inputs: config.stream_inputs,
+
             // Network
             address,
 
+            // Liveness
+            heartbeat_interval: config.heartbeat_interval,
+            max_missed_heartbeats: config.max_missed_heartbeats,
+
             // Sender Channels
             idle_tx,
             failed_tx,
             retry_operation_tx,
-        
+
+            // Receiver Channels
+            idle_rx: tokio::sync::Mutex::new(idle_rx),
+            failed_rx: tokio::sync::Mutex::new(failed_rx),
+            retry_operation_rx: tokio::sync::Mutex::new(retry_operation_rx),
+
             // Workers handling
             workers: Mutex::new(Vec::new()),
+            failed_workers: Mutex::new(Vec::new()),
             total_workers: Mutex::new(0),
-        };
-  
-        master
+
+            // Map inputs
+            sources: Mutex::new(HashMap::new()),
+
+            // Map outputs
+            map_operations: Mutex::new(0),
+            map_outputs: Mutex::new(HashMap::new()),
+        }
     }
-}
 
-/* Master Main Function ***********************************************************************************************/
-#[tokio::main]
-async fn main() {
-    // Channels for idle and failed workers, as well as fault tolerance.
-    let (idle_worker_tx, 
-         mut idle_worker_rx) = channel::<master_remoteworker::RemoteWorker>(IDLE_WORKER_BUFFER);
-    let (fail_worker_tx, 
-         mut fail_worker_rx) = channel::<master_remoteworker::RemoteWorker>(FAILED_WORKER_BUFFER);
-    let (retry_operation_tx, _) = channel(RETRY_OPERATION_BUFFER);
-
-    // Listen to idle channel
-    tokio::spawn(async move {
-        while let Some(msg) = idle_worker_rx.recv().await {
-            println!("New worker registered!");
-            println!("ID: {}, Hostname: {}", msg.id, msg.hostname);
-
-            std::thread::sleep(std::time::Duration::from_secs(1));
-
-            let mut run_client = RunnerClient::connect("http://[::1]:8081").await.unwrap();
-            let request = tonic::Request::new(RunArgs{
-                id: {0},
-                file_path: {"./src/data/alice.txt".to_string()},
-            });
-
-            run_client.run_map(request).await.expect("Failed to run map.");
-
-            let request = tonic::Request::new(RunArgs{
-                id: {0},
-                file_path: {"./src/data/alice.txt".to_string()},
-            });
-            match run_client.run_reduce(request).await {
-                Ok(_) => {
-                    println!("Reduce completed successfully!");
+    // HandleFailingWorkers will handle workers that fail while running an operation. They are removed from
+    // the list of workers and kept in the list of failed workers, so they are never scheduled again.
+    pub async fn handle_failing_workers(&self) {
+        let mut failed_rx = self.failed_rx.lock().await;
+
+        while let Some(mut failed_worker) = failed_rx.recv().await {
+            let mut workers = self.workers.lock().unwrap();
+
+            // The same worker may be reported by both the scheduler and the heartbeat monitor.
+            if let Some(position) = workers.iter().position(|worker| worker.id == failed_worker.id) {
+                println!("Removing worker {} from master list.", failed_worker.id);
+
+                workers.remove(position);
+                failed_worker.mark_dead();
+                self.failed_workers.lock().unwrap().push(failed_worker);
+            }
+        }
+    }
+
+    // MonitorWorkers will periodically check when each worker was last seen, and mark the ones that
+    // missed too many heartbeats as dead, even if they are idle.
+    pub async fn monitor_workers(&self) {
+        let max_silence = self.heartbeat_interval * self.max_missed_heartbeats;
+
+        loop {
+            tokio::time::sleep(self.heartbeat_interval).await;
+
+            let dead_workers: Vec<master_remoteworker::RemoteWorker> = self.workers.lock().unwrap().iter_mut()
+                .filter(|worker| worker.status != WorkerStatus::Dead && worker.last_seen.elapsed() > max_silence)
+                .map(|worker| {
+                    println!("Worker {} missed {} heartbeats.", worker.id, self.max_missed_heartbeats);
+                    worker.mark_dead();
+                    worker.clone()
+                })
+                .collect();
+
+            for dead_worker in dead_workers {
+                // The map outputs stored in the dead worker can't be fetched anymore.
+                for operation in self.lose_map_outputs(&dead_worker.hostname) {
+                    if self.retry_operation_tx.send(operation).await.is_err() {
+                        eprintln!("Failed to send operation to retry channel.");
+                    }
                 }
-                Err(_) => {
-                    std::process::exit(1);
+
+                if self.failed_tx.send(dead_worker).await.is_err() {
+                    eprintln!("Failed to send worker to failed channel.");
                 }
-            
             }
         }
-    });
+    }
+
+    // Check if a worker is still registered and alive.
+    pub fn is_alive(&self, id: usize) -> bool {
+        self.workers.lock().unwrap().iter()
+            .any(|worker| worker.id == id && worker.status != WorkerStatus::Dead)
+    }
 
-    // Listen to failed worker channel
-    tokio::spawn(async move {
-        while let Some(_) = fail_worker_rx.recv().await {
-            
+    // Update the status of a worker in the list of workers.
+    pub fn set_status(&self, id: usize, status: WorkerStatus) {
+        if let Some(worker) = self.workers.lock().unwrap().iter_mut().find(|worker| worker.id == id) {
+            if status == WorkerStatus::Dead {
+                worker.mark_dead();
+            } else if worker.status != WorkerStatus::Dead {
+                worker.status = status;
+            }
         }
-    });
+    }
+
+    // Record the output of a completed map operation, stored in the worker with the given hostname.
+    pub fn store_map_output(&self, hostname: &str, operation: common::Operation) {
+        self.map_outputs.lock().unwrap().insert(operation.id, MapOutput {
+            hostname: hostname.to_string(),
+            operation,
+        });
+    }
+
+    // Forget the map outputs stored in a worker, returning the operations that must be run again.
+    pub fn lose_map_outputs(&self, hostname: &str) -> Vec<common::Operation> {
+        let mut map_outputs = self.map_outputs.lock().unwrap();
+        let lost_ids: Vec<i32> = map_outputs.iter()
+            .filter(|(_, output)| output.hostname == hostname)
+            .map(|(id, _)| *id)
+            .collect();
 
-    let address = "[::1]:8080".parse().unwrap();
-    let master = Master::new_master(address, idle_worker_tx, fail_worker_tx, retry_operation_tx);
+        let mut lost_operations: Vec<common::Operation> = lost_ids.iter()
+            .filter_map(|id| map_outputs.remove(id))
+            .map(|output| output.operation)
+            .collect();
+        lost_operations.sort_by_key(|operation| operation.id);
+
+        lost_operations
+    }
 
-    Server::builder().add_service(RegisterServer::new(master))
-    .serve(address)
-    .await.expect("Failed to start RPC server.");
-}
\ No newline at end of file
+    // Hostname of the worker holding the output of each map operation, by map id. Returns None while
+    // some of them are missing.
+    pub fn map_hostnames(&self) -> Option<Vec<String>> {
+        let map_operations = *self.map_operations.lock().unwrap();
+        let map_outputs = self.map_outputs.lock().unwrap();
+
+        (0..map_operations)
+            .map(|id| map_outputs.get(&id).map(|output| output.hostname.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,92 @@
+/* General Imports ****************************************************************************************************/
+use distributedrust::{cli, data, mapreduce};
+use clap::App;
+use std::fs;
+use std::process::ExitCode;
+
+/* Master Main Function ***********************************************************************************************/
+#[tokio::main]
+async fn main() -> ExitCode {
+    let matches = App::new("MapReduce Master")
+        .args(&cli::reduce_args())
+        // Input data settings
+        .args(&cli::input_args())
+        .args(&cli::input_format_args())
+        // Job settings
+        .args(&cli::job_args())
+        // Network settings
+        .args(&cli::network_args("5000"))
+        // Worker liveness settings
+        .args(&cli::liveness_args())
+        // Input transfer settings
+        .args(&cli::transfer_args())
+        // Intermediate file settings
+        .args(&cli::format_args())
+        .get_matches();
+
+    let hostname = cli::hostname(&matches);
+    let reduce_jobs = cli::reduce_jobs(&matches);
+    let inputs = cli::inputs(&matches);
+    let chunk_size = cli::chunk_size(&matches);
+    let (heartbeat_interval, max_missed_heartbeats) = cli::liveness(&matches);
+    let stream_inputs = cli::stream_inputs(&matches);
+    let input_format = match cli::input_format(&matches) {
+        Ok(input_format) => input_format,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let format = match cli::format(&matches) {
+        Ok(format) => format,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("Hostname: {}", hostname);
+    println!("Reduce jobs: {}", reduce_jobs);
+    println!("Files: {}", inputs.join(", "));
+    println!("Chunk size: {}", chunk_size);
+
+    let files = match data::input_files(&inputs) {
+        Ok(files) => files,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let _ = fs::create_dir(data::MAP_PATH);
+    let _ = fs::create_dir(data::REDUCE_PATH);
+    let _ = fs::create_dir(data::RESULT_PATH);
+
+    if let Err(err) = data::remove_contents(data::MAP_PATH) {
+        eprintln!("Error removing contents: {}", err);
+    }
+    if let Err(err) = data::remove_contents(data::RESULT_PATH) {
+        eprintln!("Error removing contents: {}", err);
+    }
+
+    let mut task = match cli::job_task(&matches) {
+        Ok(task) => task,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    task.set_num_reduce_jobs(reduce_jobs);
+    task.set_format(format);
+
+    let splits = data::split_data(&files, chunk_size, input_format);
+    *task.input_file_path_chan() = data::fan_in_file_path(splits.len() as i32);
+
+    match mapreduce::run_master(task.as_mut(), hostname, stream_inputs, heartbeat_interval, max_missed_heartbeats).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            println!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
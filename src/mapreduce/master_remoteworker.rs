@@ -1,7 +1,13 @@
-#[derive(Debug, Clone)]
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum WorkerStatus {
   Idle,
   Running,
+  Dead,
 }
 
 #[derive(Debug, Clone)]
@@ -9,16 +15,32 @@ pub struct RemoteWorker {
   pub id: usize,
   pub hostname: String,
   pub status: WorkerStatus,
+  pub last_seen: Instant,   // Last time the master received a heartbeat from the worker
+  pub dead: Arc<watch::Sender<bool>>, // Set once the worker is marked as dead, shared by every copy of it
 }
 
 impl RemoteWorker {
     pub fn new_worker(id: usize, hostname: String) -> RemoteWorker {
-        let worker = RemoteWorker {
+        RemoteWorker {
             id,
             hostname,
             status: WorkerStatus::Idle,
-        };
+            last_seen: Instant::now(),
+            dead: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    // Mark the worker as dead, which stops the operations that depend on it.
+    pub fn mark_dead(&mut self) {
+        self.status = WorkerStatus::Dead;
+        self.dead.send_replace(true);
+    }
 
-        return worker;
+    // Wait until the worker is marked as dead.
+    pub fn until_dead(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut dead = self.dead.subscribe();
+        async move {
+            let _ = dead.wait_for(|dead| *dead).await;
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,404 @@
+/* General Imports ****************************************************************************************************/
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinSet;
+use tonic::Status;
+use prost::Message;
+
+use crate::common::Operation;
+use crate::data;
+use crate::master::Master;
+use crate::master_remoteworker::{RemoteWorker, WorkerStatus};
+
+/* Tonic RPC generated stubs ******************************************************************************************/
+use crate::common_rpc::runner_client::RunnerClient;            // Master is the client in the runner service.
+use crate::common_rpc::{FetchResultArgs, RunArgs};              // Import message types.
+use crate::common_rpc::{ErrorDetail, ErrorKind};
+
+/* Basic Definitions **************************************************************************************************/
+pub const RUN_MAP: &str = "Runner.RunMap";
+pub const RUN_REDUCE: &str = "Runner.RunReduce";
+
+// Time a worker has to complete an operation before it's considered failed.
+pub const OPERATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Number of times an operation can fail because of its input before the task is aborted.
+pub const MAX_OPERATION_ATTEMPTS: u32 = 3;
+
+// Outcome of running an operation in a remote worker.
+enum OperationResult {
+    Completed(String, i32), // The procedure and id of the operation that completed.
+    Retrying,           // The operation was sent to the retry channel.
+    Aborted(String),    // The operation can't be completed.
+}
+
+/* Scheduler Implementation *******************************************************************************************/
+impl Master {
+    // Schedule will wait for file paths on the file_path_chan and, for each one of them, create an
+    // Operation and hand it to the next idle worker. Operations that failed are received through the
+    // retry channel and handed to another worker. Reduce operations wait until the output of every map
+    // operation is available, so map operations whose output was lost are run again first. It returns
+    // the number of operations scheduled once the channel is closed and all of them are completed, or an
+    // error if an operation can't be completed.
+    pub async fn schedule(self: &Arc<Self>, proc: &str, file_path_chan: &mut Receiver<String>)
+                          -> Result<i32, String> {
+        let mut operations = JoinSet::new();
+        let mut pending = VecDeque::new();
+        let mut completed = HashSet::new();
+        let mut inputs_open = true;
+        let mut counter = 0;
+
+        loop {
+            let mut retry_operation_rx = self.retry_operation_rx.lock().await;
+
+            tokio::select! {
+                Some(operation) = retry_operation_rx.recv() => {
+                    println!("Retrying {} '{}'.", operation.proc, operation.id);
+
+                    // Completed operations are sent back if the worker holding their result failed.
+                    if operation.proc == proc {
+                        completed.remove(&operation.id);
+                    }
+                    pending.push_back(operation);
+                }
+
+                file_path = file_path_chan.recv(), if inputs_open => match file_path {
+                    Some(file_path) => {
+                        counter += 1;
+                        pending.push_back(Operation {
+                            proc: proc.to_string(),
+                            file_path,
+                            id: counter - 1,
+                            attempts: 0,
+                        });
+                    }
+
+                    None => inputs_open = false,
+                },
+
+                Some(result) = operations.join_next() => {
+                    match result {
+                        // Map operations that are run again in the reduce phase are not counted.
+                        Ok(OperationResult::Completed(completed_proc, id)) if completed_proc == proc => {
+                            completed.insert(id);
+                        }
+                        Ok(OperationResult::Aborted(error)) => return Err(error),
+                        _ => (),
+                    }
+                }
+            };
+            drop(retry_operation_rx);
+
+            while let Some(operation) = self.next_runnable_operation(&mut pending) {
+                // Wait for a worker to be available.
+                let worker = match self.next_idle_worker().await {
+                    Some(worker) => worker,
+                    None => return Err("No workers available.".to_string()),
+                };
+                self.set_status(worker.id, WorkerStatus::Running);
+
+                let master = Arc::clone(self);
+                operations.spawn(async move {
+                    master.run_operation(worker, operation).await
+                });
+            }
+
+            if !inputs_open && operations.is_empty() && pending.is_empty() && completed.len() == counter as usize {
+                break;
+            }
+        }
+
+        Ok(counter)
+    }
+
+    // Take the first pending operation that can run. Reduce operations can only run once the output of
+    // every map operation is available.
+    fn next_runnable_operation(&self, pending: &mut VecDeque<Operation>) -> Option<Operation> {
+        let map_outputs_ready = self.map_hostnames().is_some();
+        let position = pending.iter()
+            .position(|operation| operation.proc != RUN_REDUCE || map_outputs_ready)?;
+
+        pending.remove(position)
+    }
+
+    // NextIdleWorker will wait for an idle worker that is still alive. Idle workers that were marked
+    // as dead have the map outputs they were holding sent to the retry channel.
+    async fn next_idle_worker(&self) -> Option<RemoteWorker> {
+        loop {
+            let worker = self.idle_rx.lock().await.recv().await?;
+
+            if self.is_alive(worker.id) {
+                return Some(worker);
+            }
+
+            println!("Skipping dead worker {}.", worker.id);
+            self.retry_operations(self.lose_map_outputs(&worker.hostname)).await;
+        }
+    }
+
+    // RunOperation will call the remote worker to run the operation, and send it back to the idle
+    // channel once it is done. If the call fails or times out, the worker is sent to the failed channel
+    // and the operation to the retry channel, along with the map operations whose output it was holding.
+    // Operations that failed because of their input are retried in other workers, which are kept, up
+    // to MAX_OPERATION_ATTEMPTS times.
+    async fn run_operation(&self, remote_worker: RemoteWorker, mut operation: Operation) -> OperationResult {
+        println!("Running {} '{}' on worker {}.", operation.proc, operation.id, remote_worker.id);
+
+        // Map outputs may be lost after the operation was scheduled, so it has to wait for them again.
+        let args = match self.run_args(&operation) {
+            Some(args) => args,
+            None => {
+                self.set_status(remote_worker.id, WorkerStatus::Idle);
+                if self.idle_tx.send(remote_worker).await.is_err() {
+                    eprintln!("Failed to send worker back to idle channel.");
+                }
+                self.retry_operations(vec![operation]).await;
+
+                return OperationResult::Retrying;
+            }
+        };
+
+        let result = self.call_while_alive(&remote_worker, &operation, args).await;
+
+        match result {
+            Ok(_) => {
+                let completed = OperationResult::Completed(operation.proc.clone(), operation.id);
+                if operation.proc == RUN_MAP {
+                    // The output of a worker that was marked as dead meanwhile can't be relied on.
+                    if !self.is_alive(remote_worker.id) {
+                        self.retry_operations(vec![operation]).await;
+                        return OperationResult::Retrying;
+                    }
+                    self.store_map_output(&remote_worker.hostname, operation);
+                }
+                self.set_status(remote_worker.id, WorkerStatus::Idle);
+
+                if self.idle_tx.send(remote_worker).await.is_err() {
+                    eprintln!("Failed to send worker back to idle channel.");
+                }
+
+                completed
+            }
+
+            Err(error) if is_input_error(&error) => {
+                eprintln!("Operation {} '{}' failed because of its input. Error: {}", operation.proc, operation.id,
+                          error.message());
+
+                // The worker is fine, so it can run other operations.
+                self.set_status(remote_worker.id, WorkerStatus::Idle);
+                if self.idle_tx.send(remote_worker).await.is_err() {
+                    eprintln!("Failed to send worker back to idle channel.");
+                }
+
+                operation.attempts += 1;
+                if operation.attempts >= MAX_OPERATION_ATTEMPTS {
+                    return OperationResult::Aborted(format!("{} '{}' failed {} times. Last error: {}",
+                                                            operation.proc, operation.id, operation.attempts,
+                                                            error.message()));
+                }
+
+                self.retry_operations(vec![operation]).await;
+
+                OperationResult::Retrying
+            }
+
+            Err(error) if partition_holder(&error).is_some() => {
+                let hostname = partition_holder(&error).unwrap_or_default();
+                eprintln!("Operation {} '{}' could not fetch a partition from {}. Error: {}", operation.proc,
+                          operation.id, hostname, error.message());
+
+                // The worker is fine, so it can run other operations.
+                self.set_status(remote_worker.id, WorkerStatus::Idle);
+                if self.idle_tx.send(remote_worker).await.is_err() {
+                    eprintln!("Failed to send worker back to idle channel.");
+                }
+
+                // The worker holding the partition is considered failed, and the map operations whose output
+                // it was holding are run again before the operation.
+                let holder = self.workers.lock().unwrap().iter()
+                    .find(|worker| worker.hostname == hostname)
+                    .cloned();
+                if let Some(holder) = holder {
+                    self.set_status(holder.id, WorkerStatus::Dead);
+                    if self.failed_tx.send(holder).await.is_err() {
+                        eprintln!("Failed to send worker to failed channel.");
+                    }
+                }
+
+                let lost_operations = self.lose_map_outputs(&hostname);
+                self.retry_operations(lost_operations.into_iter().chain(std::iter::once(operation)).collect()).await;
+
+                OperationResult::Retrying
+            }
+
+            Err(error) => {
+                eprintln!("Operation {} '{}' failed. Error: {}", operation.proc, operation.id, error);
+
+                // Map outputs stored in the failed worker are lost, so they have to be computed again.
+                let lost_operations = self.lose_map_outputs(&remote_worker.hostname);
+
+                if self.failed_tx.send(remote_worker).await.is_err() {
+                    eprintln!("Failed to send worker to failed channel.");
+                }
+
+                self.retry_operations(lost_operations.into_iter().chain(std::iter::once(operation)).collect()).await;
+
+                OperationResult::Retrying
+            }
+        }
+    }
+
+    // Call the procedure of the operation in the worker, until it completes or times out. It's stopped as soon
+    // as the worker is marked as dead, such as when it stops sending heartbeats, and reduce operations are
+    // also stopped as soon as a worker holding one of their map outputs is.
+    async fn call_while_alive(&self, remote_worker: &RemoteWorker, operation: &Operation, args: RunArgs)
+                              -> Result<(), Status> {
+        let mut holders_dead = JoinSet::new();
+        for holder in self.workers.lock().unwrap().iter()
+            .filter(|worker| worker.id != remote_worker.id && args.map_hostnames.contains(&worker.hostname)) {
+            let hostname = holder.hostname.clone();
+            let until_dead = holder.until_dead();
+            holders_dead.spawn(async move {
+                until_dead.await;
+                hostname
+            });
+        }
+        let holder_dead = async {
+            match holders_dead.join_next().await {
+                Some(Ok(hostname)) => hostname,
+                _ => std::future::pending().await,
+            }
+        };
+
+        let call = tokio::time::timeout(OPERATION_TIMEOUT, call_remote_worker(remote_worker, operation, args));
+        tokio::select! {
+            result = call => match result {
+                Ok(result) => result,
+                Err(_) => Err(Status::deadline_exceeded("Operation timed out.")),
+            },
+            _ = remote_worker.until_dead() => {
+                Err(Status::unavailable(format!("Worker {} was marked as dead.", remote_worker.id)))
+            }
+            hostname = holder_dead => Err(holder_dead_error(&hostname)),
+        }
+    }
+
+    // Build the arguments of an operation. Reduce operations are told where to fetch each map output from,
+    // so there are no arguments for them while some map output is missing. When inputs are streamed, map
+    // operations carry the content of their input. They also carry the input file it was split from.
+    fn run_args(&self, operation: &Operation) -> Option<RunArgs> {
+        let map_hostnames = match operation.proc.as_str() {
+            RUN_REDUCE => self.map_hostnames()?,
+            _ => Vec::new(),
+        };
+
+        // If the input can't be read, the worker is left to report it as an input error.
+        let data = match operation.proc.as_str() {
+            RUN_MAP if self.stream_inputs => std::fs::read(&operation.file_path)
+                .map_err(|error| eprintln!("Failed to read input {}. Error: {}", operation.file_path, error))
+                .ok(),
+            _ => None,
+        };
+
+        let source = match operation.proc.as_str() {
+            RUN_MAP => self.sources.lock().unwrap().get(&operation.file_path).cloned(),
+            _ => None,
+        };
+
+        Some(RunArgs {
+            id: operation.id,
+            file_path: operation.file_path.clone(),
+            map_hostnames,
+            data,
+            source,
+        })
+    }
+
+    // Send operations to the retry channel, in order.
+    async fn retry_operations(&self, operations: Vec<Operation>) {
+        for operation in operations {
+            if self.retry_operation_tx.send(operation).await.is_err() {
+                eprintln!("Failed to send operation to retry channel.");
+            }
+        }
+    }
+}
+
+// Connect to the remote worker and call the procedure described by the operation.
+async fn call_remote_worker(remote_worker: &RemoteWorker, operation: &Operation, args: RunArgs) -> Result<(), Status> {
+    let mut run_client = RunnerClient::connect(format!("http://{}", remote_worker.hostname)).await
+        .map_err(|error| Status::unavailable(format!("Failed to connect to worker: {}", error)))?;
+    let request = tonic::Request::new(args);
+
+    match operation.proc.as_str() {
+        RUN_MAP => {
+            run_client.run_map(request).await?;
+        }
+        RUN_REDUCE => {
+            let reply = run_client.run_reduce(request).await?.into_inner();
+            println!("Reduce '{}' stored {} records ({} bytes) in {}.", operation.id, reply.records, reply.bytes,
+                     reply.file_path);
+
+            // The result is stored in the worker, which may not share storage with the master.
+            fetch_result(&mut run_client, operation.id).await?;
+        }
+        _ => return Err(Status::unimplemented(format!("Unknown procedure {}", operation.proc))),
+    }
+
+    Ok(())
+}
+
+// Fetch the result of a reduce operation from the worker that ran it, and store it as the local result of
+// the reduce job. It's written to a separate file first, since the worker may have stored it in the same path.
+async fn fetch_result(run_client: &mut RunnerClient<tonic::transport::Channel>, id_reduce: i32) -> Result<(), Status> {
+    let storage_error = |error: std::io::Error| Status::internal(format!("Failed to store reduce result: {}", error));
+
+    let file_path = data::fetched_result_file_name(id_reduce);
+    let mut file = File::create(&file_path).map_err(storage_error)?;
+
+    let request = tonic::Request::new(FetchResultArgs {
+        reduce_id: id_reduce,
+    });
+    let mut stream = run_client.fetch_result(request).await?.into_inner();
+    while let Some(chunk) = stream.message().await? {
+        file.write_all(&chunk.data).map_err(storage_error)?;
+    }
+
+    fs::rename(&file_path, data::result_file_name(id_reduce)).map_err(storage_error)
+}
+
+// Errors caused by the input of the operation, such as a missing or corrupted file. The worker that returned
+// them is working, and the operation may succeed in another worker.
+fn is_input_error(status: &Status) -> bool {
+    match ErrorDetail::decode(status.details()) {
+        Ok(detail) => matches!(detail.kind(), ErrorKind::InputNotFound | ErrorKind::InputCorrupted),
+        Err(_) => false,
+    }
+}
+
+// Hostname of the worker a partition could not be fetched from, if that's why the operation failed. The
+// worker that returned the error is working, but the map output has to be computed again.
+fn partition_holder(status: &Status) -> Option<String> {
+    match ErrorDetail::decode(status.details()) {
+        Ok(detail) if detail.kind() == ErrorKind::PartitionUnavailable => Some(detail.hostname),
+        _ => None,
+    }
+}
+
+// A worker holding a map output needed by the operation was marked as dead while it ran, so the operation
+// can't fetch it. It's reported like the worker running the operation would.
+fn holder_dead_error(hostname: &str) -> Status {
+    let message = format!("Worker {} holding a map output was marked as dead.", hostname);
+    let detail = ErrorDetail {
+        kind: ErrorKind::PartitionUnavailable as i32,
+        file_path: String::new(),
+        message: message.clone(),
+        hostname: hostname.to_string(),
+    };
+
+    Status::with_details(tonic::Code::Unavailable, message, detail.encode_to_vec().into())
+}
@@ -0,0 +1,249 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path;
+
+use crate::common::{Key, KeyValue, Value};
+use crate::data;
+use crate::record::{Format, RecordReader, RecordWriter};
+
+// Memory used to buffer the sorted runs merged by a reduce job. Each open run takes RUN_BUFFER_SIZE, so the
+// budget bounds the number of runs merged at once. Reduce jobs with more runs merge them in several passes.
+pub const MERGE_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+const RUN_BUFFER_SIZE: usize = 64 * 1024;
+
+type RunReader<K, V> = RecordReader<BufReader<File>, K, V>;
+
+// SortedRuns is a k-way merge of files with key/value pairs sorted by key, which yields every pair in key
+// order holding a single pair of each run in memory. Pairs with the same key are yielded in the order of
+// their runs, so the result doesn't depend on the order the runs were written.
+pub struct SortedRuns<K: Key, V: Value> {
+    runs: Vec<RunReader<K, V>>,
+    values: Vec<Option<V>>,                 // Value of the next pair of each run, whose key is in the heap
+    heap: BinaryHeap<Reverse<(K, usize)>>,  // Key of the next pair of each run, and the run
+    spilled: Vec<path::PathBuf>,            // Runs written by the intermediate passes, removed on drop
+}
+
+impl<K: Key, V: Value> SortedRuns<K, V> {
+    // Open the sorted runs of a reduce job. If there are more runs than the memory budget allows to merge at
+    // once, they are merged in groups into bigger runs first, until there are few enough. These are written
+    // in the given format.
+    pub fn open(id_reduce: i32, runs: Vec<path::PathBuf>, format: Format) -> io::Result<SortedRuns<K, V>> {
+        SortedRuns::open_with_fan_in(id_reduce, runs, format, (MERGE_MEMORY_BUDGET / RUN_BUFFER_SIZE).max(2))
+    }
+
+    // Open the sorted runs, merging at most fan_in runs at once.
+    fn open_with_fan_in(id_reduce: i32, runs: Vec<path::PathBuf>, format: Format, fan_in: usize)
+                        -> io::Result<SortedRuns<K, V>> {
+        let mut runs = runs;
+        let mut spilled = Vec::new();
+        let mut pass = 0;
+
+        while runs.len() > fan_in {
+            let mut merged_runs = Vec::new();
+            let result = SortedRuns::<K, V>::merge_pass(id_reduce, pass, &runs, format, fan_in, &mut merged_runs);
+
+            // The runs of the previous pass are merged, so they are no longer needed. Neither are the runs of
+            // this pass if it failed.
+            remove_runs(&spilled);
+            if let Err(error) = result {
+                remove_runs(&merged_runs);
+                return Err(error);
+            }
+            spilled = merged_runs.clone();
+            runs = merged_runs;
+            pass += 1;
+        }
+
+        SortedRuns::open_runs(&runs, spilled)
+    }
+
+    // Merge the runs in groups of fan_in, adding the path of each merged run to merged_runs as soon as it's
+    // created.
+    fn merge_pass(id_reduce: i32, pass: i32, runs: &[path::PathBuf], format: Format, fan_in: usize,
+                  merged_runs: &mut Vec<path::PathBuf>) -> io::Result<()> {
+        for (i, group) in runs.chunks(fan_in).enumerate() {
+            let merged_path = data::spill_file_name(id_reduce, pass, i as i32);
+            let file = File::create(&merged_path)?;
+            merged_runs.push(merged_path);
+
+            let mut writer = RecordWriter::new(BufWriter::new(file), format)?;
+            for kv in SortedRuns::<K, V>::open_runs(group, Vec::new())? {
+                writer.write(&kv?)?;
+            }
+            writer.finish()?;
+        }
+
+        Ok(())
+    }
+
+    fn open_runs(runs: &[path::PathBuf], spilled: Vec<path::PathBuf>) -> io::Result<SortedRuns<K, V>> {
+        let mut sorted_runs = SortedRuns {
+            runs: Vec::with_capacity(runs.len()),
+            values: Vec::with_capacity(runs.len()),
+            heap: BinaryHeap::with_capacity(runs.len()),
+            spilled,
+        };
+
+        for run in runs {
+            let reader = BufReader::with_capacity(RUN_BUFFER_SIZE, File::open(run)?);
+            sorted_runs.runs.push(RecordReader::new(reader)?);
+            sorted_runs.values.push(None);
+        }
+
+        for run in 0..sorted_runs.runs.len() {
+            sorted_runs.advance(run)?;
+        }
+
+        Ok(sorted_runs)
+    }
+
+    // Read the next pair of a run into the heap.
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        match self.runs[run].next() {
+            Some(Ok(kv)) => {
+                self.values[run] = Some(kv.value);
+                self.heap.push(Reverse((kv.key, run)));
+                Ok(())
+            }
+            Some(Err(error)) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<K: Key, V: Value> Iterator for SortedRuns<K, V> {
+    type Item = io::Result<KeyValue<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((key, run)) = self.heap.pop()?;
+        let value = self.values[run].take()?;
+
+        // A run that can't be read ends the merge, since its pairs would be missing from the result.
+        if let Err(error) = self.advance(run) {
+            self.heap.clear();
+            return Some(Err(error));
+        }
+
+        Some(Ok(KeyValue { key, value }))
+    }
+}
+
+impl<K: Key, V: Value> Drop for SortedRuns<K, V> {
+    fn drop(&mut self) {
+        self.runs.clear();
+        remove_runs(&self.spilled);
+    }
+}
+
+fn remove_runs(runs: &[path::PathBuf]) {
+    for run in runs {
+        let _ = fs::remove_file(run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::REDUCE_PATH;
+    use crate::test_cluster::RunDir;
+
+    // Pairs of a run, sorted by key.
+    type Run = Vec<(&'static str, u64)>;
+
+    // Write each run, returning their paths.
+    fn write_runs(runs: &[Run]) -> Vec<path::PathBuf> {
+        runs.iter().enumerate()
+            .map(|(i, run)| {
+                let run_path = path::PathBuf::from(format!("run-{}", i));
+                let mut writer = RecordWriter::new(BufWriter::new(File::create(&run_path).unwrap()), Format::Binary)
+                    .unwrap();
+                for (key, value) in run {
+                    writer.write(&KeyValue { key: key.to_string(), value: *value }).unwrap();
+                }
+                writer.finish().unwrap();
+                run_path
+            })
+            .collect()
+    }
+
+    fn merge(runs: Vec<path::PathBuf>, fan_in: usize) -> io::Result<Vec<(String, u64)>> {
+        SortedRuns::<String, u64>::open_with_fan_in(0, runs, Format::Binary, fan_in)?
+            .map(|kv| kv.map(|kv| (kv.key, kv.value)))
+            .collect()
+    }
+
+    fn spill_files() -> usize {
+        fs::read_dir(REDUCE_PATH).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("spill-"))
+            .count()
+    }
+
+    // Runs whose pairs share keys across runs. The value of each pair is the index of its run, so the order of
+    // pairs with the same key can be checked.
+    fn overlapping_runs(n: u64) -> (Vec<Run>, Vec<(String, u64)>) {
+        // Each run leaves out some of the keys.
+        let keys = ["a", "b", "c", "d"];
+        let runs: Vec<Run> = (0..n)
+            .map(|run| {
+                keys.iter().enumerate()
+                    .filter(|(i, _)| !(*i as u64 + run).is_multiple_of(3))
+                    .map(|(_, key)| (*key, run))
+                    .collect()
+            })
+            .collect();
+
+        let mut expected: Vec<_> = runs.iter().flatten().map(|(key, run)| (key.to_string(), *run)).collect();
+        expected.sort();
+        (runs, expected)
+    }
+
+    #[tokio::test]
+    async fn merge_in_key_and_run_order() {
+        let _run = RunDir::enter("merge").await;
+
+        let (runs, expected) = overlapping_runs(3);
+        assert_eq!(merge(write_runs(&runs), 3).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn merge_in_several_passes() {
+        let _run = RunDir::enter("merge").await;
+
+        // Ten runs, merged two at a time: into five runs, three, two and then the result.
+        let (runs, expected) = overlapping_runs(10);
+        assert_eq!(merge(write_runs(&runs), 2).unwrap(), expected);
+        assert_eq!(spill_files(), 0);
+    }
+
+    #[tokio::test]
+    async fn merge_corrupt_run() {
+        let _run = RunDir::enter("merge").await;
+
+        let (runs, _) = overlapping_runs(4);
+        let run_paths = write_runs(&runs);
+        let mut data = fs::read(&run_paths[1]).unwrap();
+        data.truncate(data.len() - 1);
+        fs::write(&run_paths[1], data).unwrap();
+
+        // Whether the run is merged at once or in an intermediate pass, the error isn't lost, and the runs of
+        // the intermediate passes are removed.
+        for fan_in in [4, 2] {
+            assert_eq!(merge(run_paths.clone(), fan_in).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(spill_files(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn spilled_runs_removed_on_drop() {
+        let _run = RunDir::enter("merge").await;
+
+        let (runs, _) = overlapping_runs(5);
+        let sorted_runs = SortedRuns::<String, u64>::open_with_fan_in(0, write_runs(&runs), Format::Binary, 2).unwrap();
+        assert_eq!(spill_files(), 2);
+
+        drop(sorted_runs);
+        assert_eq!(spill_files(), 0);
+    }
+}
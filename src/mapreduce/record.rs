@@ -0,0 +1,373 @@
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::marker::PhantomData;
+
+use crate::common::{Key, KeyValue, Value};
+
+// Binary files start with MAGIC followed by the code of their format, so readers can tell them apart from
+// JSON files, which always start with '{'.
+const MAGIC: &[u8; 4] = b"MRKV";
+const BINARY_CODE: u8 = 1;
+const LZ4_CODE: u8 = 2;
+
+// Size of the uncompressed blocks of the lz4 format.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// Largest frame that can be written or read, so a corrupted length can't make readers allocate without bound.
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+// Format is the encoding of the key/value pairs in intermediate files. Readers detect the format of each
+// file, so it only needs to be chosen by the writers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,   // One JSON object per line, readable by people.
+    Binary, // Each pair encoded with bincode, after its length.
+    Lz4,    // Binary pairs, grouped in blocks compressed with lz4.
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "binary" => Some(Format::Binary),
+            "lz4" => Some(Format::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Binary => "binary",
+            Format::Lz4 => "lz4",
+        }
+    }
+}
+
+// RecordWriter encodes key/value pairs in one of the formats. finish must be called once every pair is
+// written, since the lz4 format buffers the last block.
+pub struct RecordWriter<W: Write> {
+    writer: W,
+    format: Format,
+    record: Vec<u8>,    // Encoded pair being written
+    block: Vec<u8>,     // Pairs of the block being filled, for the lz4 format
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(mut writer: W, format: Format) -> io::Result<RecordWriter<W>> {
+        match format {
+            Format::Json => {}
+            Format::Binary => write_header(&mut writer, BINARY_CODE)?,
+            Format::Lz4 => write_header(&mut writer, LZ4_CODE)?,
+        }
+
+        Ok(RecordWriter {
+            writer,
+            format,
+            record: Vec::new(),
+            block: Vec::new(),
+        })
+    }
+
+    // Write a key/value pair. Returns the number of bytes of the encoded pair, before compression.
+    pub fn write<K: Key, V: Value>(&mut self, kv: &KeyValue<K, V>) -> io::Result<u64> {
+        self.record.clear();
+
+        match self.format {
+            Format::Json => {
+                serde_json::to_writer(&mut self.record, kv)?;
+                self.record.push(b'\n');
+                self.writer.write_all(&self.record)?;
+            }
+            Format::Binary => {
+                bincode::serialize_into(&mut self.record, kv).map_err(invalid_data)?;
+                write_frame(&mut self.writer, &self.record)?;
+            }
+            Format::Lz4 => {
+                bincode::serialize_into(&mut self.record, kv).map_err(invalid_data)?;
+                write_frame(&mut self.block, &self.record)?;
+                if self.block.len() >= BLOCK_SIZE {
+                    self.write_block()?;
+                }
+            }
+        }
+
+        Ok(self.record.len() as u64)
+    }
+
+    // Write the pairs still buffered and flush the writer, which is returned.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.len() > MAX_FRAME_SIZE {
+            return Err(invalid_data(format!("Block too large: {} bytes", self.block.len())));
+        }
+
+        let compressed = lz4_flex::compress_prepend_size(&self.block);
+        write_frame(&mut self.writer, &compressed)?;
+        self.block.clear();
+
+        Ok(())
+    }
+}
+
+// RecordReader decodes the key/value pairs of a file written by RecordWriter, in whichever format it was
+// written.
+pub struct RecordReader<R: BufRead, K: Key, V: Value> {
+    reader: R,
+    format: Format,
+    record: Vec<u8>,            // Encoded pair being read
+    block: Cursor<Vec<u8>>,     // Uncompressed block being read, for the lz4 format
+    pairs: PhantomData<fn() -> KeyValue<K, V>>,
+}
+
+impl<R: BufRead, K: Key, V: Value> RecordReader<R, K, V> {
+    // Detect the format of the file from its first bytes.
+    pub fn new(mut reader: R) -> io::Result<RecordReader<R, K, V>> {
+        let format = match reader.fill_buf()?.first() {
+            Some(byte) if *byte == MAGIC[0] => {
+                let mut header = [0; MAGIC.len() + 1];
+                reader.read_exact(&mut header)?;
+                if &header[..MAGIC.len()] != MAGIC {
+                    return Err(invalid_data("Unknown file format"));
+                }
+
+                match header[MAGIC.len()] {
+                    BINARY_CODE => Format::Binary,
+                    LZ4_CODE => Format::Lz4,
+                    code => return Err(invalid_data(format!("Unknown binary format: {}", code))),
+                }
+            }
+            _ => Format::Json,
+        };
+
+        Ok(RecordReader {
+            reader,
+            format,
+            record: Vec::new(),
+            block: Cursor::new(Vec::new()),
+            pairs: PhantomData,
+        })
+    }
+
+    fn read_pair(&mut self) -> io::Result<Option<KeyValue<K, V>>> {
+        match self.format {
+            Format::Json => loop {
+                self.record.clear();
+                if self.reader.read_until(b'\n', &mut self.record)? == 0 {
+                    return Ok(None);
+                }
+
+                // Blank lines don't hold any pair.
+                if !self.record.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(Some(serde_json::from_slice(&self.record)?));
+                }
+            },
+            Format::Binary => {
+                if !read_frame(&mut self.reader, &mut self.record)? {
+                    return Ok(None);
+                }
+
+                bincode::deserialize(&self.record).map(Some).map_err(invalid_data)
+            }
+            Format::Lz4 => {
+                while !read_frame(&mut self.block, &mut self.record)? {
+                    // The block is over, so decompress the next one.
+                    if !read_frame(&mut self.reader, &mut self.record)? {
+                        return Ok(None);
+                    }
+
+                    let mut block = std::mem::take(self.block.get_mut());
+                    decompress_block(&self.record, &mut block)?;
+                    self.block = Cursor::new(block);
+                }
+
+                bincode::deserialize(&self.record).map(Some).map_err(invalid_data)
+            }
+        }
+    }
+}
+
+impl<R: BufRead, K: Key, V: Value> Iterator for RecordReader<R, K, V> {
+    type Item = io::Result<KeyValue<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_pair().transpose()
+    }
+}
+
+fn write_header(writer: &mut impl Write, code: u8) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[code])
+}
+
+// Write data after its length, as a little-endian u32.
+fn write_frame(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(invalid_data(format!("Record too large: {} bytes", data.len())));
+    }
+
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+// Read the data written by write_frame. Returns false if the reader is over, before any byte of the frame.
+fn read_frame(reader: &mut impl BufRead, data: &mut Vec<u8>) -> io::Result<bool> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(false);
+    }
+
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data(format!("Record too large: {} bytes", len)));
+    }
+
+    // The data is read as it comes, rather than allocated up front, so a truncated file fails without
+    // allocating the whole length.
+    data.clear();
+    if reader.take(len as u64).read_to_end(data)? != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated record"));
+    }
+
+    Ok(true)
+}
+
+// Decompress a block written by write_block into block. Its size is prepended to it, and is checked before
+// allocating, like the length of frames.
+fn decompress_block(compressed: &[u8], block: &mut Vec<u8>) -> io::Result<()> {
+    let (size, compressed) = compressed.split_first_chunk::<4>()
+        .ok_or_else(|| invalid_data("Truncated block"))?;
+
+    let size = u32::from_le_bytes(*size) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(invalid_data(format!("Block too large: {} bytes", size)));
+    }
+
+    block.clear();
+    block.resize(size, 0);
+    if lz4_flex::decompress_into(compressed, block).map_err(invalid_data)? != size {
+        return Err(invalid_data("Truncated block"));
+    }
+
+    Ok(())
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 3] = [Format::Json, Format::Binary, Format::Lz4];
+
+    fn pairs(n: usize) -> Vec<KeyValue<String, u64>> {
+        (0..n).map(|i| KeyValue { key: format!("key-{:06}", i), value: i as u64 }).collect()
+    }
+
+    fn write_pairs(format: Format, pairs: &[KeyValue<String, u64>]) -> Vec<u8> {
+        let mut writer = RecordWriter::new(Vec::new(), format).unwrap();
+        for kv in pairs {
+            writer.write(kv).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read_pairs(data: &[u8]) -> io::Result<Vec<(String, u64)>> {
+        RecordReader::<_, String, u64>::new(data)?.map(|kv| kv.map(|kv| (kv.key, kv.value))).collect()
+    }
+
+    fn as_tuples(pairs: &[KeyValue<String, u64>]) -> Vec<(String, u64)> {
+        pairs.iter().map(|kv| (kv.key.clone(), kv.value)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let pairs = pairs(100);
+
+        for format in FORMATS {
+            assert_eq!(read_pairs(&write_pairs(format, &pairs)).unwrap(), as_tuples(&pairs), "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn round_trip_empty_file() {
+        for format in FORMATS {
+            assert_eq!(read_pairs(&write_pairs(format, &[])).unwrap(), vec![], "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn round_trip_across_blocks() {
+        // Enough pairs to fill several lz4 blocks, the last one partly.
+        let pairs = pairs(3 * BLOCK_SIZE / 16);
+
+        for format in FORMATS {
+            assert_eq!(read_pairs(&write_pairs(format, &pairs)).unwrap(), as_tuples(&pairs), "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn round_trip_block_boundary() {
+        // Pairs whose frames fill a block exactly: the length of the key and the value take 16 bytes, and the
+        // length of the frame 4 more.
+        let kv = KeyValue { key: "k".repeat(BLOCK_SIZE / 4 - 20), value: 1u64 };
+        let pairs = vec![kv; 8];
+
+        let data = write_pairs(Format::Lz4, &pairs);
+        assert_eq!(read_pairs(&data).unwrap(), as_tuples(&pairs));
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut data = MAGIC.to_vec();
+        data.push(BINARY_CODE);
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(read_pairs(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn block_too_large() {
+        // A block whose prepended size is too large, which must fail before the size is allocated.
+        let mut block = u32::MAX.to_le_bytes().to_vec();
+        block.extend_from_slice(&lz4_flex::compress(b"pairs"));
+
+        let mut data = MAGIC.to_vec();
+        data.push(LZ4_CODE);
+        write_frame(&mut data, &block).unwrap();
+
+        assert_eq!(read_pairs(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn block_size_mismatch() {
+        // A block that holds less than its prepended size.
+        let mut block = 100u32.to_le_bytes().to_vec();
+        block.extend_from_slice(&lz4_flex::compress(b"pairs"));
+
+        let mut data = MAGIC.to_vec();
+        data.push(LZ4_CODE);
+        write_frame(&mut data, &block).unwrap();
+
+        assert_eq!(read_pairs(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut data = write_pairs(Format::Binary, &pairs(1));
+        data.pop();
+
+        assert_eq!(read_pairs(&data).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
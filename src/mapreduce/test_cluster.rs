@@ -0,0 +1,313 @@
+// Test harness that runs a master and its workers inside the runtime of a test, so the RPC path can be
+// tested end to end. Jobs are also run with RunSequential and RunParallel, so the results can be compared.
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::common::RunnableTask;
+use crate::data::{self, InputFormat, MAP_PATH, REDUCE_PATH, RESULT_PATH};
+use crate::jobs::{self, Job};
+use crate::mapreduce;
+use crate::record::Format;
+use crate::worker::FailMode;
+
+// Nodes store their data in directories relative to the working directory, which belongs to the whole
+// process. Runs take turns, each in its own directory.
+static WORKING_DIR: OnceLock<Mutex<()>> = OnceLock::new();
+
+// Liveness settings of the cluster, short so failing workers are noticed quickly.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+// How long a job can run in the cluster, so a cluster that can't finish it fails the test instead of hanging.
+const JOB_TIMEOUT: Duration = Duration::from_secs(120);
+
+// How long workers have to exit once the master is done.
+const WORKER_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// TestJob is a job run by the harness, with the settings of the main binary.
+#[derive(Debug, Clone)]
+pub struct TestJob {
+    pub jobs: &'static [Job],   // Jobs the master can run
+    pub job: String,
+    pub job_args: String,
+    pub inputs: Vec<PathBuf>,
+    pub input_format: InputFormat,
+    pub chunk_size: usize,
+    pub reduce_jobs: i32,
+    pub format: Format,
+    pub stream_inputs: bool,
+}
+
+impl TestJob {
+    // A job over the book used by the examples, with the default settings.
+    pub fn new(job: &str, job_args: &str) -> TestJob {
+        TestJob {
+            jobs: jobs::JOBS,
+            job: job.to_string(),
+            job_args: job_args.to_string(),
+            inputs: vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("files/pg1342.txt")],
+            input_format: InputFormat::Text,
+            chunk_size: 102400,
+            reduce_jobs: 5,
+            format: Format::Json,
+            stream_inputs: false,
+        }
+    }
+
+    fn task(&self) -> Box<dyn RunnableTask> {
+        let mut task = jobs::new_job_task(self.jobs, &self.job, &self.job_args).expect("unknown job");
+        task.set_num_reduce_jobs(self.reduce_jobs);
+        task.set_format(self.format);
+        task
+    }
+}
+
+// TestWorker is a worker of the cluster, which can be told to fail like the worker binary. Workers can't
+// exit, since they run in the process of the test.
+#[derive(Debug, Clone, Copy)]
+pub struct TestWorker {
+    pub n_ops: i32,
+    pub fail_mode: FailMode,
+    pub jobs: &'static [Job],
+}
+
+impl TestWorker {
+    pub fn new() -> TestWorker {
+        TestWorker {
+            n_ops: 0,
+            fail_mode: FailMode::Error,
+            jobs: jobs::JOBS,
+        }
+    }
+
+    pub fn failing(n_ops: i32, fail_mode: FailMode) -> TestWorker {
+        assert!(fail_mode != FailMode::Exit, "workers of the test cluster can't exit");
+        TestWorker { n_ops, fail_mode, jobs: jobs::JOBS }
+    }
+
+    // A worker that knows none of the jobs, so the master can't use it.
+    pub fn without_jobs() -> TestWorker {
+        TestWorker { jobs: &[], ..TestWorker::new() }
+    }
+}
+
+impl Default for TestWorker {
+    fn default() -> TestWorker {
+        TestWorker::new()
+    }
+}
+
+// Result of a job: the content of the result of each reduce job.
+pub type JobResult = Vec<String>;
+
+// Run the job with RunSequential.
+pub async fn run_sequential(job: &TestJob) -> JobResult {
+    let _run = RunDir::enter("sequential").await;
+
+    let mut task = job.task();
+    let splits = data::split_data(&job.inputs, job.chunk_size, job.input_format);
+    *task.input_chan() = data::fan_in_data(splits);
+
+    mapreduce::run_sequential(task.as_mut()).await;
+
+    read_results(job.reduce_jobs)
+}
+
+// Run the job with RunParallel, in the given number of threads.
+pub async fn run_parallel(job: &TestJob, threads: usize) -> JobResult {
+    let _run = RunDir::enter("parallel").await;
+
+    let mut task = job.task();
+    let splits = data::split_data(&job.inputs, job.chunk_size, job.input_format);
+    *task.input_chan() = data::fan_in_data(splits);
+
+    // RunParallel blocks until the task is done, while the inputs are sent by the runtime.
+    tokio::task::block_in_place(|| mapreduce::run_parallel(task.as_mut(), threads));
+
+    read_results(job.reduce_jobs)
+}
+
+// Run the job in a master and the workers, each listening on a free localhost port of this process.
+pub async fn run_cluster(job: &TestJob, workers: &[TestWorker]) -> JobResult {
+    let _run = RunDir::enter("cluster").await;
+
+    let mut task = job.task();
+    let splits = data::split_data(&job.inputs, job.chunk_size, job.input_format);
+    *task.input_file_path_chan() = data::fan_in_file_path(splits.len() as i32);
+
+    let master_hostname = format!("localhost:{}", free_port());
+
+    // Workers are started once the master listens, so they don't wait to register again.
+    let master = mapreduce::run_master(task.as_mut(), master_hostname.clone(), job.stream_inputs, HEARTBEAT_INTERVAL,
+                                       MAX_MISSED_HEARTBEATS);
+    let start_workers = async {
+        wait_until_listening(&master_hostname).await;
+
+        workers.iter()
+            .map(|worker| {
+                let hostname = format!("localhost:{}", free_port());
+                tokio::spawn(mapreduce::run_worker(hostname, master_hostname.clone(), worker.jobs, worker.n_ops,
+                                                   worker.fail_mode))
+            })
+            .collect::<Vec<_>>()
+    };
+    let (result, worker_nodes) = tokio::time::timeout(JOB_TIMEOUT, async { tokio::join!(master, start_workers) })
+        .await
+        .expect("the cluster didn't finish the job");
+    result.expect("the cluster failed the job");
+
+    // Workers exit once the master tells them the job is done, and workers that can't run the job exit with
+    // an error as soon as they register. Hung workers never do.
+    for (node, worker) in worker_nodes.into_iter().zip(workers) {
+        let abort = node.abort_handle();
+        match tokio::time::timeout(WORKER_EXIT_TIMEOUT, node).await {
+            Ok(result) => {
+                let result = result.expect("the worker panicked");
+                assert_eq!(result.is_ok(), !worker.jobs.is_empty(), "unexpected worker result: {:?}", result);
+            }
+            Err(_) => abort.abort(),
+        }
+    }
+
+    read_results(job.reduce_jobs)
+}
+
+// RunDir is the working directory of a run, with the data directories of the nodes. The previous working
+// directory is restored, and the run directory removed, when it's dropped. Other tests that store data in
+// the working directory take it too.
+pub struct RunDir {
+    path: PathBuf,
+    previous: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl RunDir {
+    pub async fn enter(name: &str) -> RunDir {
+        let lock = WORKING_DIR.get_or_init(|| Mutex::new(())).lock().await;
+
+        let path = env::temp_dir().join(format!("distributedrust-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        for dir in [MAP_PATH, REDUCE_PATH, RESULT_PATH] {
+            fs::create_dir_all(path.join(dir)).unwrap();
+        }
+
+        let previous = env::current_dir().unwrap();
+        env::set_current_dir(&path).unwrap();
+
+        RunDir {
+            path,
+            previous,
+            _lock: lock,
+        }
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.previous);
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn read_results(reduce_jobs: i32) -> JobResult {
+    (0..reduce_jobs)
+        .map(|r| fs::read_to_string(data::result_file_name(r)).unwrap_or_default())
+        .collect()
+}
+
+// Ask the system for a free port. It's released right away, so a node can listen on it.
+fn free_port() -> u16 {
+    TcpListener::bind("localhost:0").unwrap().local_addr().unwrap().port()
+}
+
+async fn wait_until_listening(hostname: &str) {
+    while TcpStream::connect(hostname).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::master_scheduler::OPERATION_TIMEOUT;
+
+    fn workers(n: usize) -> Vec<TestWorker> {
+        vec![TestWorker::new(); n]
+    }
+
+    fn assert_same_result(sequential: &JobResult, result: &JobResult) {
+        assert!(sequential.iter().any(|result| !result.is_empty()), "the job has no result");
+        assert_eq!(sequential, result);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parallel_matches_sequential() {
+        // Smaller splits, so every thread runs several map operations.
+        let mut job = TestJob::new("invertedindex", "");
+        job.chunk_size = 20000;
+
+        assert_same_result(&run_sequential(&job).await, &run_parallel(&job, 4).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn word_count_matches_sequential() {
+        let job = TestJob::new("wordcount", "");
+
+        assert_same_result(&run_sequential(&job).await, &run_cluster(&job, &workers(3)).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_lz4_inverted_index_matches_sequential() {
+        let mut job = TestJob::new("invertedindex", "");
+        job.format = Format::Lz4;
+        job.stream_inputs = true;
+        job.chunk_size = 50000;
+
+        assert_same_result(&run_sequential(&job).await, &run_cluster(&job, &workers(2)).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failing_worker_matches_sequential() {
+        let job = TestJob::new("wordcount", "");
+        let workers = [
+            TestWorker::new(),
+            TestWorker::new(),
+            TestWorker::failing(3, FailMode::Error),
+        ];
+
+        assert_same_result(&run_sequential(&job).await, &run_cluster(&job, &workers).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn worker_without_the_job_is_rejected() {
+        let job = TestJob::new("wordcount", "");
+        let workers = [TestWorker::new(), TestWorker::without_jobs()];
+
+        assert_same_result(&run_sequential(&job).await, &run_cluster(&job, &workers).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hanging_worker_matches_sequential() {
+        let job = TestJob::new("wordcount", "");
+        let workers = [
+            TestWorker::new(),
+            TestWorker::new(),
+            TestWorker::failing(3, FailMode::Hang),
+        ];
+
+        // The operations of the hung worker are run again once it misses its heartbeats, rather than when they
+        // time out.
+        let started = std::time::Instant::now();
+        let cluster = run_cluster(&job, &workers).await;
+        assert!(started.elapsed() < OPERATION_TIMEOUT, "the job waited for the operations of the hung worker");
+
+        assert_same_result(&run_sequential(&job).await, &cluster);
+    }
+}
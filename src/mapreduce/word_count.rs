@@ -1,61 +1,75 @@
-use crate::common::{Task, KeyValue};
+use crate::common::{KeyValue, RunnableTask, Task};
 
-use std::collections::hash_map::{HashMap, DefaultHasher};
+use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
 
 // KeyValue is the type used to hold elements of maps and reduces results.
+// Words are the keys, and the number of times they appear the values.
 
+pub fn new_task(_: &str) -> Result<Box<dyn RunnableTask>, String> {
+    Ok(Box::new(Task::new_task(map_func, shuffle_func, reduce_func)
+        .with_combiner(reduce_func)
+        .with_preprocessor(normalize)))
+}
 
-// This function receives a string obtained from the split files.
-pub fn map_func(buffer: &Vec<u8>) -> Vec<KeyValue> {
-    // This considers UTF-8 encoding.
-    let words = match std::str::from_utf8(buffer) {
-        Ok(v) => v,
-        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-    };
-
-    // Make all characters lowercase, and remove the punctuation. Then, separate words by whitespace.
-    let words = words.to_ascii_lowercase().replace(&['(', ')', ',', '\"', '.', ';', ':', '\'', '`', '-', '?', '_', '!'][..], "");
-    let split = words.split_ascii_whitespace();
-    
+// This function receives a string obtained from the split files, normalized by the preprocessor.
+pub fn map_func(_: &str, buffer: &[u8]) -> Vec<KeyValue<String, u64>> {
     // Create a vector to store the words.
-    let mut result: Vec<KeyValue> = Vec::new();
+    let mut result: Vec<KeyValue<String, u64>> = Vec::new();
 
-    for word in split {
-        result.push(KeyValue{key: word.to_string(), value: "1".to_string()})
+    for word in split_words(buffer) {
+        result.push(KeyValue{key: word, value: 1})
     }
 
-    return result
+    result
 }
 
-pub fn reduce_func(inputs: &mut Vec<KeyValue>) -> &mut Vec<KeyValue> {
-    // Hash map for counting number of times each word appears. It would be 
-    // best to use the hash map from the beginning, but then the map and reduce 
-    // functions would be done at the same time.
-    let mut element_count = HashMap::new();
-    for element in inputs.clone() {
-        *element_count.entry(element.key).or_insert(0) += 1;
-    }
+// Make all characters lowercase, and remove the punctuation, so words are the same wherever they appear. It's
+// the preprocessor of the jobs that split their input in words.
+pub fn normalize(buffer: &[u8]) -> Vec<u8> {
+    buffer.iter()
+        .filter(|byte| !b"(),\".;:'`-?_!".contains(byte))
+        .map(u8::to_ascii_lowercase)
+        .collect()
+}
 
-    // Clear vector.
-    inputs.clear();
+// Split the input in words, separated by whitespace, which are also used by other jobs.
+pub fn split_words(buffer: &[u8]) -> Vec<String> {
+    // Inputs may not be valid UTF-8, so invalid sequences are replaced rather than failing the operation.
+    let words = String::from_utf8_lossy(buffer);
 
-    // Update values in the vector
-    for (element, count) in &element_count {
-        inputs.push(KeyValue { key: (element.to_string()), value: (*count).to_string() });
-    }
+    words.split_ascii_whitespace().map(|word| word.to_string()).collect()
+}
 
-    return inputs;
+// Add up the number of times a word appears.
+pub fn reduce_func(_: &String, counts: &mut dyn Iterator<Item = u64>) -> u64 {
+    counts.sum()
 }
 
-pub fn shuffle_func(task: &Task, key: String) -> i32 {
+// Hash the key to choose its reduce job. It's used by the other jobs as well, whatever their key type is.
+pub fn shuffle_func<K: Hash>(key: &K, num_reduce_jobs: i32) -> i32 {
     let mut hasher = DefaultHasher::new();
     key.hash(&mut hasher);
     let hash_value = hasher.finish();
 
-    let num_reduce_jobs = task.num_reduce_jobs as u64;
+    let num_reduce_jobs = num_reduce_jobs as u64;
     let reduce_job = Wrapping(hash_value) % Wrapping(num_reduce_jobs);
 
     reduce_job.0 as i32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_words_utf8() {
+        assert_eq!(split_words("il était  une\nfois".as_bytes()), vec!["il", "était", "une", "fois"]);
+    }
+
+    #[test]
+    fn split_words_invalid_utf8() {
+        assert_eq!(split_words(b"valid \xff\xfeword end"), vec!["valid", "\u{FFFD}\u{FFFD}word", "end"]);
+    }
+}
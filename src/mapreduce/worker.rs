@@ -1,32 +1,88 @@
 /* General Imports ****************************************************************************************************/
-mod common;
-mod word_count;
-mod data;
+use tonic::{Code, Request, Response, Status};
+use prost::Message;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio::sync::Notify;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use tonic::{transport::Server, Request, Response, Status};
+use crate::common::{self, OperationError};
+use crate::data;
+use crate::jobs;
+use crate::record::Format;
 
 /* Tonic RPC generated stubs ******************************************************************************************/
-use common_rpc::register_client::RegisterClient;              // Worker is the client in the register service.
+use crate::common_rpc::register_client::RegisterClient;         // Worker is the client in the register service.
                                                                 // Client can be used without direct implementation.
-use common_rpc::runner_server::{Runner, RunnerServer};          // Worker is the server in the runner service.
-use common_rpc::{RegisterArgs, RunArgs, EmptyMessage};   // Import message types.
+use crate::common_rpc::runner_client::RunnerClient;             // Reducers are clients of the workers holding map outputs.
+use crate::common_rpc::runner_server::Runner;                   // Worker is the server in the runner service.
+use crate::common_rpc::{RegisterArgs, HeartbeatArgs, RunArgs, ReduceReply, EmptyMessage};   // Import message types.
+use crate::common_rpc::{FetchPartitionArgs, FetchResultArgs, PartitionChunk};
+use crate::common_rpc::{ErrorDetail, ErrorKind};
 
-pub mod common_rpc {
-    tonic::include_proto!("common_rpc");                        // This string must match the proto name.
+/* Basic Definitions **************************************************************************************************/
+// Size of the chunks a partition is streamed in, and number of chunks buffered while streaming.
+const PARTITION_CHUNK_SIZE: usize = 64 * 1024;
+const PARTITION_CHUNK_BUFFER: usize = 4;
+
+// FailMode is the way a worker behaves once it reaches the number of operations it should run before failing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailMode {
+    Exit,   // Exit the process.
+    Hang,   // Stop answering RPCs and sending heartbeats.
+    Error,  // Return an error for every RPC.
 }
 
-/* Basic Definitions **************************************************************************************************/
+impl FailMode {
+    pub fn from_name(name: &str) -> Option<FailMode> {
+        match name {
+            "exit" => Some(FailMode::Exit),
+            "hang" => Some(FailMode::Hang),
+            "error" => Some(FailMode::Error),
+            _ => None,
+        }
+    }
+}
+
+// RegisterError tells apart the registrations worth trying again, such as when the master can't be reached yet,
+// from workers that can't run the job of the master, which never will.
 #[derive(Debug)]
+pub enum RegisterError {
+    Failed(String),
+    Unsupported(String),
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegisterError::Failed(message) | RegisterError::Unsupported(message) => write!(f, "{}", message),
+        }
+    }
+}
+
 pub struct Worker {
-    id: i32,
+    pub id: i32,
 
     // Network
-    hostname: String,
-    master_hostname: String,
+    pub hostname: String,
+    pub master_hostname: String,
+    pub heartbeat_interval: Duration,
 
     // Operation
-    task: common::Task,
-    done: bool,
+    pub jobs: &'static [jobs::Job],                     // Jobs the master can ask the worker to run
+    pub task: Option<Arc<dyn common::RunnableTask>>,     // Job sent by the master when the worker registers
+    pub done: Notify,
+
+    // Induced failure
+    n_ops: i32,                         // Number of operations to run before failure (0 = no failure)
+    fail_mode: FailMode,
+    task_counter: Mutex<i32>,
+    failed: AtomicBool,
 }
 
 /* Worker RPCs ********************************************************************************************************/
@@ -36,42 +92,49 @@ impl Runner for Worker {
         &self,
         request: Request<RunArgs>,
     ) -> Result<Response<EmptyMessage>, Status> {
-        let args = request.into_inner();
+        self.induce_failure().await?;
+
+        let mut args = request.into_inner();
         println!("Running map ID: {}, Path: {}", args.id, args.file_path.clone());
 
-        // Open, read file and perform map.
-        let read_string = std::fs::read_to_string(args.file_path.clone());
-        match read_string {
-            Ok(read_string) => {
-                let map_result = (self.task.map)(&read_string.as_bytes().to_vec());
-
-                // Store the result locally.
-                let local_store = data::store_local(&self.task, args.id, &map_result);
-
-                match local_store {
-                    Ok(_) => {
-                        println!("Finished map ID: {}, Path: {}", args.id, args.file_path.clone());
-                        
-                        Ok(Response::new(common_rpc::EmptyMessage {
-                        }))
-                    }
-
-                    Err(error) => {
-                        println!("Map result storage failure with error: {}", error);
-                        println!("Dumping map result...");
-                        
-                        for pair in map_result {
-                            println!("{}: {}", pair.key, pair.value);
-                        }
-                        
-                        std::process::exit(1);
-                    }
-                }
+        // Use the content sent by the master, or read the file, and perform map. Both run in a blocking
+        // thread, so the runtime keeps sending heartbeats while they do. Inputs are passed to map as they
+        // are, so jobs decide how to decode them.
+        let task = Arc::clone(self.task.as_ref().ok_or_else(not_registered_error)?);
+        let data = args.data.take();
+        let (args, result) = tokio::task::spawn_blocking(move || {
+            let input = match data {
+                Some(data) => Ok(data),
+                None => std::fs::read(&args.file_path),
+            };
+
+            // Perform map and store the result locally. Map functions see the input file of the split, if
+            // the master knows it.
+            let result = input.map(|input| {
+                let name = args.source.as_deref().unwrap_or(&args.file_path);
+                task.run_map(args.id, name, &input)
+            });
+            (args, result)
+        }).await.map_err(operation_panicked)?;
+
+        match result {
+            Ok(Ok(_)) => {
+                println!("Finished map ID: {}, Path: {}", args.id, args.file_path.clone());
+                self.count_operation();
+
+                Ok(Response::new(EmptyMessage {
+                }))
+            }
+
+            Ok(Err(OperationError::Input(error))) | Ok(Err(OperationError::Storage(error))) => {
+                println!("Map result storage failure with error: {}", error);
+                Err(operation_error(Code::Internal, ErrorKind::StorageFailure, &args.file_path,
+                                    format!("Failed to store map result: {}", error)))
             }
 
             Err(error) => {
                 println!("Error reading input file: {}", error);
-                std::process::exit(1);
+                Err(input_error(&error, &args.file_path))
             }
         }
     }
@@ -79,104 +142,331 @@ impl Runner for Worker {
     async fn run_reduce(
         &self,
         request: Request<RunArgs>,
-    ) -> Result<Response<EmptyMessage>, Status> {
+    ) -> Result<Response<ReduceReply>, Status> {
+        self.induce_failure().await?;
+
         let args = request.into_inner();
         println!("Running reduce ID: {}, Path: {}", args.id, args.file_path.clone());
 
-        // Try to load map result from local storage.
-        let mut file_opening = data::load_local(args.id);
-        match file_opening {
-            Ok(ref mut map_result) => {
-                let reduce_result = (self.task.reduce)(map_result);
+        // Pull the partition of the reduce job from every worker holding map outputs.
+        let runs = self.fetch_partitions(args.id, &args.map_hostnames).await?;
 
-                for pair in reduce_result {
-                    println!("{}: {}", pair.key, pair.value);
-                }
+        // Merge the map results from local storage, perform reduce and store the result, in the same format
+        // used by the sequential mode. Like map, it runs in a blocking thread.
+        let task = Arc::clone(self.task.as_ref().ok_or_else(not_registered_error)?);
+        let id_reduce = args.id;
+        let result = tokio::task::spawn_blocking(move || task.run_reduce(id_reduce, runs))
+            .await
+            .map_err(operation_panicked)?;
+
+        match result {
+            Ok(summary) => {
+                let file_path = data::result_file_name(args.id);
+                println!("Finished reduce ID: {}, Path: {}", args.id, file_path.display());
+                self.count_operation();
+
+                Ok(Response::new(ReduceReply {
+                    file_path: file_path.display().to_string(),
+                    records: summary.records as i64,
+                    bytes: summary.bytes as i64,
+                }))
             }
 
-            Err(error) => {
+            Err(OperationError::Storage(error)) => {
+                println!("Reduce result storage failure with error: {}", error);
+                Err(operation_error(Code::Internal, ErrorKind::StorageFailure, &args.file_path,
+                                    format!("Failed to store reduce result: {}", error)))
+            }
+
+            Err(OperationError::Input(error)) => {
                 println!("Error reading map file: {}", error);
-                std::process::exit(1);
+                Err(input_error(&error, &args.file_path))
             }
-        }     
+        }
+    }
+
+    type FetchPartitionStream = ReceiverStream<Result<PartitionChunk, Status>>;
+
+    async fn fetch_partition(
+        &self,
+        request: Request<FetchPartitionArgs>,
+    ) -> Result<Response<Self::FetchPartitionStream>, Status> {
+        self.induce_failure().await?;
+
+        let args = request.into_inner();
+        let file_path = data::partition_file_name(args.map_id, args.reduce_id);
+        let file = File::open(&file_path).map_err(|error| input_error(&error, &file_path.display().to_string()))?;
+
+        Ok(Response::new(stream_file(file, file_path)))
+    }
+
+    type FetchResultStream = ReceiverStream<Result<PartitionChunk, Status>>;
+
+    async fn fetch_result(
+        &self,
+        request: Request<FetchResultArgs>,
+    ) -> Result<Response<Self::FetchResultStream>, Status> {
+        self.induce_failure().await?;
 
-        std::process::exit(0);
+        let args = request.into_inner();
+        let file_path = data::result_file_name(args.reduce_id);
+        let file = File::open(&file_path).map_err(|error| input_error(&error, &file_path.display().to_string()))?;
+
+        Ok(Response::new(stream_file(file, file_path)))
     }
 
     async fn done(
         &self,
         _: Request<EmptyMessage>,
     ) -> Result<Response<EmptyMessage>, Status> {
-        
+
         println!("Worker is done.");
+        self.done.notify_one();
 
-        Ok(Response::new(common_rpc::EmptyMessage {
+        Ok(Response::new(EmptyMessage {
         }))
     }
 }
 
 /* Worker Implementation **********************************************************************************************/
 impl Worker {
-    fn new_worker(id: i32, hostname: String, master_hostname: String) -> Worker {
-        let worker = Worker {
+    pub fn new_worker(id: i32, hostname: String, master_hostname: String, jobs: &'static [jobs::Job], n_ops: i32,
+                      fail_mode: FailMode) -> Worker {
+        Worker {
             id,
             hostname,
             master_hostname,
-            task: common::Task::new_task(word_count::map_func, word_count::shuffle_func, word_count::reduce_func),
-            done: false,
-        };
-    
-        return worker;
+            heartbeat_interval: Duration::from_secs(1),
+            jobs,
+            task: None,
+            done: Notify::new(),
+            n_ops,
+            fail_mode,
+            task_counter: Mutex::new(0),
+            failed: AtomicBool::new(false),
+        }
     }
 
-    async fn register(&mut self) {
+    // Register with the master and load its job. The master only accepts workers that know its job, but the
+    // job may still fail to load, such as when its arguments are wrong for it, and then the worker can't run it.
+    pub async fn register(&mut self) -> Result<(), RegisterError> {
         println!("\nRegistering with master...");
-        let mut reg_client = RegisterClient::connect(self.master_hostname.clone()).await;
-
-        match reg_client {
-            Ok(ref mut reg_client) => {
-                let request = tonic::Request::new(RegisterArgs {
-                    worker_hostname: self.hostname.clone(),
-                });
-        
-                let response = reg_client.register(request).await;
-                match response {
-                    Ok(response) => {
-                        let args = response.into_inner();
-                        println!("Registered with ID {} and ReduceJobs {}.", args.worker_id, args.reduce_jobs);
-        
-                        self.id = args.worker_id;
-                        self.task.num_reduce_jobs = args.reduce_jobs;
-                    }
-        
-                    Err(_) => {
-                        println!("Registration with master failed !")
-                    }
+        let mut reg_client = RegisterClient::connect(format!("http://{}", self.master_hostname)).await
+            .map_err(|error| {
+                println!("Connection with master has been refused !\nAddress used was: {}", self.master_hostname);
+                RegisterError::Failed(error.to_string())
+            })?;
+
+        let request = tonic::Request::new(RegisterArgs {
+            worker_hostname: self.hostname.clone(),
+            jobs: self.jobs.iter().map(|job| job.name.to_string()).collect(),
+        });
+
+        let args = match reg_client.register(request).await {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::FailedPrecondition => {
+                return Err(RegisterError::Unsupported(status.message().to_string()));
+            }
+            Err(status) => {
+                println!("Registration with master failed !");
+                return Err(RegisterError::Failed(status.to_string()));
+            }
+        };
+        println!("Registered with ID {}, ReduceJobs {} and Job {}.", args.worker_id, args.reduce_jobs, args.job);
+
+        // Load the same job as the master.
+        let mut task = jobs::new_job_task(self.jobs, &args.job, &args.job_args)
+            .map_err(|error| RegisterError::Unsupported(format!("Loading job {} failed: {}", args.job, error)))?;
+        task.set_num_reduce_jobs(args.reduce_jobs);
+
+        // Store map outputs in the format chosen by the master.
+        let format = match Format::from_name(&args.format) {
+            Some(format) => format,
+            None => return Err(RegisterError::Unsupported(format!("Unknown intermediate format: {}", args.format))),
+        };
+        task.set_format(format);
+
+        self.id = args.worker_id;
+        self.task = Some(Arc::from(task));
+        if args.heartbeat_interval > 0 {
+            self.heartbeat_interval = Duration::from_millis(args.heartbeat_interval as u64);
+        }
+
+        Ok(())
+    }
+
+    // Periodically signal the master that the worker is alive. Hung workers stop sending heartbeats.
+    pub async fn heartbeat(&self) {
+        let mut reg_client = None;
+
+        loop {
+            tokio::time::sleep(self.heartbeat_interval).await;
+
+            if self.is_hung() {
+                return;
+            }
+
+            // Connect again if the master could not be reached.
+            if reg_client.is_none() {
+                reg_client = RegisterClient::connect(format!("http://{}", self.master_hostname)).await.ok();
+            }
+
+            if let Some(ref mut client) = reg_client {
+                if let Err(error) = client.heartbeat(tonic::Request::new(HeartbeatArgs { worker_id: self.id })).await {
+                    println!("Heartbeat failed with error: {}", error.message());
                 }
             }
+        }
+    }
 
-            Err(_) => {
-                println!("Connection with master has been refused !\nAddress used was: {}", self.master_hostname);
+    // Fetch the output of every map operation for a reduce job into local files, and return their paths in
+    // the order of the map operations. Map outputs held by this worker are read in place.
+    async fn fetch_partitions(&self, id_reduce: i32, map_hostnames: &[String]) -> Result<Vec<PathBuf>, Status> {
+        let mut runs = Vec::with_capacity(map_hostnames.len());
+
+        for (id_map, hostname) in map_hostnames.iter().enumerate() {
+            let id_map = id_map as i32;
+            let file_path = data::partition_file_name(id_map, id_reduce);
+            let file_path_str = file_path.display().to_string();
+
+            if *hostname == self.hostname {
+                runs.push(file_path);
+                continue;
             }
+
+            // Copies are stored apart from the map outputs of this worker, which it may still be serving.
+            let fetched_file_path = data::fetched_partition_file_name(id_map, id_reduce);
+            let mut fetched_file = File::create(&fetched_file_path)
+                .map_err(|error| operation_error(Code::Internal, ErrorKind::StorageFailure,
+                                                 &fetched_file_path.display().to_string(),
+                                                 format!("Failed to create partition file: {}", error)))?;
+
+            if let Err(error) = fetch_partition(hostname, id_map, id_reduce, &mut fetched_file).await {
+                println!("Failed to fetch partition {} from {}. Error: {}", file_path_str, hostname, error);
+                return Err(partition_error(hostname, &file_path_str, error));
+            }
+            runs.push(fetched_file_path);
         }
+
+        Ok(runs)
+    }
+
+    // Count a completed operation, for induced failures.
+    fn count_operation(&self) {
+        *self.task_counter.lock().unwrap() += 1;
+    }
+
+    // Once the worker completed the number of operations it should run, every RPC fails according to the
+    // fail mode.
+    async fn induce_failure(&self) -> Result<(), Status> {
+        if self.n_ops <= 0 || *self.task_counter.lock().unwrap() < self.n_ops {
+            return Ok(());
+        }
+
+        if !self.failed.swap(true, Ordering::SeqCst) {
+            println!("Inducing failure ({:?}) after {} operations.", self.fail_mode, self.n_ops);
+        }
+
+        match self.fail_mode {
+            FailMode::Exit => std::process::exit(1),
+            FailMode::Hang => std::future::pending().await,
+            FailMode::Error => Err(operation_error(Code::Unavailable, ErrorKind::InducedFailure, "",
+                                                   "Induced failure.".to_string())),
+        }
+    }
+
+    fn is_hung(&self) -> bool {
+        self.fail_mode == FailMode::Hang && self.failed.load(Ordering::SeqCst)
     }
 }
 
-/* Worker Main Function ***********************************************************************************************/
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut worker = Worker::new_worker(0, "HostA".to_string(), "http://[::1]:8080".to_string());
-    
-    // Worker must wait for registration to complete before doing anything else.
-    worker.register().await;
-
-    // Since registration is complete, start up the RPC server, and perform other tasks.
-    let address = "[::1]:8081".parse().unwrap();
-    let worker = Worker::new_worker(0, "HostA".to_string(), "MasterA".to_string());
-    
-    Server::builder().add_service(RunnerServer::new(worker))
-    .serve(address)
-    .await;
+// Build the status returned by a failed operation, with a detail the master can use to decide whether to retry.
+fn operation_error(code: Code, kind: ErrorKind, file_path: &str, message: String) -> Status {
+    let detail = ErrorDetail {
+        kind: kind as i32,
+        file_path: file_path.to_string(),
+        message: message.clone(),
+        hostname: String::new(),
+    };
+
+    Status::with_details(code, message, detail.encode_to_vec().into())
+}
+
+// Stream a partition from the worker holding it into a file.
+async fn fetch_partition(hostname: &str, id_map: i32, id_reduce: i32, file: &mut File) -> Result<(), String> {
+    let mut run_client = RunnerClient::connect(format!("http://{}", hostname)).await
+        .map_err(|error| error.to_string())?;
+    let request = tonic::Request::new(FetchPartitionArgs {
+        map_id: id_map,
+        reduce_id: id_reduce,
+    });
+
+    let mut stream = run_client.fetch_partition(request).await
+        .map_err(|error| error.message().to_string())?
+        .into_inner();
+    while let Some(chunk) = stream.message().await.map_err(|error| error.message().to_string())? {
+        file.write_all(&chunk.data).map_err(|error| error.to_string())?;
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Stream a local file in chunks. The file is read in a blocking thread, and each chunk is sent as it's read.
+fn stream_file(mut file: File, file_path: PathBuf) -> ReceiverStream<Result<PartitionChunk, Status>> {
+    let file_path_str = file_path.display().to_string();
+
+    let (chunk_tx, chunk_rx) = mpsc::channel(PARTITION_CHUNK_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0; PARTITION_CHUNK_SIZE];
+
+        loop {
+            let chunk = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => Ok(PartitionChunk { data: buffer[..size].to_vec() }),
+                Err(error) => Err(input_error(&error, &file_path_str)),
+            };
+
+            let failed = chunk.is_err();
+            if chunk_tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    ReceiverStream::new(chunk_rx)
+}
+
+// The operation panicked, or the runtime was shut down while it ran.
+fn operation_panicked(error: tokio::task::JoinError) -> Status {
+    Status::internal(format!("Operation failed to complete: {}", error))
+}
+
+// Operations can't run before the worker registers, since the master tells it the job to run.
+fn not_registered_error() -> Status {
+    Status::failed_precondition("Worker is not registered.")
+}
+
+// A partition the operation needs could not be fetched from the worker holding it.
+fn partition_error(hostname: &str, file_path: &str, message: String) -> Status {
+    let detail = ErrorDetail {
+        kind: ErrorKind::PartitionUnavailable as i32,
+        file_path: file_path.to_string(),
+        message: message.clone(),
+        hostname: hostname.to_string(),
+    };
+
+    Status::with_details(Code::Unavailable, message, detail.encode_to_vec().into())
+}
+
+// Errors reading the input of an operation. Missing inputs and inputs that can't be parsed are told apart from
+// other errors, since running the operation again won't fix them.
+fn input_error(error: &io::Error, file_path: &str) -> Status {
+    match error.kind() {
+        io::ErrorKind::NotFound => operation_error(Code::NotFound, ErrorKind::InputNotFound, file_path,
+                                                   format!("Input not found: {}", error)),
+        io::ErrorKind::InvalidData => operation_error(Code::DataLoss, ErrorKind::InputCorrupted, file_path,
+                                                      format!("Input corrupted: {}", error)),
+        _ => operation_error(Code::Internal, ErrorKind::Unknown, file_path,
+                             format!("Failed to read input: {}", error)),
+    }
+}
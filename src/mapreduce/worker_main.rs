@@ -0,0 +1,41 @@
+/* General Imports ****************************************************************************************************/
+use distributedrust::{cli, jobs, mapreduce};
+use clap::App;
+use std::process::ExitCode;
+
+/* Worker Main Function ***********************************************************************************************/
+#[tokio::main]
+async fn main() -> ExitCode {
+    let matches = App::new("MapReduce Worker")
+        // Network settings
+        .args(&cli::network_args("5001"))
+        // Induced failure on Worker
+        .args(&cli::failure_args())
+        .get_matches();
+
+    let hostname = cli::hostname(&matches);
+    let master = cli::master_hostname(&matches);
+    let (n_ops, fail_mode) = match cli::failure(&matches) {
+        Ok(failure) => failure,
+        Err(err) => {
+            println!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("Hostname: {}", hostname);
+    println!("Master: {}", master);
+
+    if n_ops > 0 {
+        println!("Induced failure ({:?})", fail_mode);
+        println!("After {} operations.", n_ops);
+    }
+
+    match mapreduce::run_worker(hostname, master, jobs::JOBS, n_ops, fail_mode).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            println!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}